@@ -1,35 +1,53 @@
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::fs::DirEntry;
-use std::fs::OpenOptions;
-use std::io::BufReader;
-use std::io::BufWriter;
-use std::io::Seek;
-use std::io::SeekFrom;
+use std::collections::BTreeMap;
 use std::path::Path;
 use std::path::PathBuf;
 
 use log::debug;
 use log::error;
 use log::info;
+use log::warn;
 
-use crate::civitai::query_model_info;
+use crate::civitai::CivitAiClient;
+use crate::civitai::CivitAiError;
+use crate::civitai::Model;
 use crate::civitai::ModelInfo;
 use crate::civitai::ModelType;
 use crate::configuration::ComfyUIConfig;
-use crate::configuration::Config;
+use crate::configuration::DetectionOrder;
 use crate::configuration::FolderStructure;
+use crate::configuration::InboxConfig;
+use crate::configuration::RenameMode;
+use crate::configuration::SortConfig;
 use crate::configuration::WebUIConfig;
+use crate::hash;
+use crate::hash::EldenError;
 use crate::hash::EldenRing;
+use crate::hash::HashOptions;
+use crate::hash::ModelHashes;
+use crate::inbox;
+use crate::interrupt;
+use crate::interrupt::Interrupted;
+use crate::link;
+use crate::link::LinkMode;
+use crate::link::ReplacePolicy;
+use crate::plan::Plan;
+use crate::safetensors;
+use crate::sidecar;
+use crate::store::Store;
+use crate::template;
 
 #[derive(Debug)]
 pub enum APIError {
     ModelNotFound(String),
     SerdeJson(String),
     EldenError(String),
-    CivitAiError(String),
+    CivitAi(CivitAiError),
     Io(String),
-    Unspecified(String),
+    Safetensors(String),
+    Sidecar(String),
+    Store(String),
+    Template(String),
+    Interrupted,
 }
 
 impl std::fmt::Display for APIError {
@@ -38,16 +56,26 @@ impl std::fmt::Display for APIError {
             APIError::ModelNotFound(msg) => write!(f, "Model not found error: {}", msg),
             APIError::SerdeJson(msg) => write!(f, "Serde JSON error: {}", msg),
             APIError::EldenError(msg) => write!(f, "Elden error: {}", msg),
-            APIError::CivitAiError(msg) => write!(f, "CivitAI error: {}", msg),
+            APIError::CivitAi(err) => write!(f, "CivitAI error: {}", err),
             APIError::Io(msg) => write!(f, "IO error: {}", msg),
-            APIError::Unspecified(msg) => write!(f, "Unspecified error: {}", msg),
+            APIError::Safetensors(msg) => write!(f, "Safetensors error: {}", msg),
+            APIError::Sidecar(msg) => write!(f, "Sidecar error: {}", msg),
+            APIError::Store(msg) => write!(f, "Store error: {}", msg),
+            APIError::Template(msg) => write!(f, "Template error: {}", msg),
+            APIError::Interrupted => write!(f, "Interrupted"),
         }
     }
 }
 
 impl From<std::io::Error> for APIError {
-    fn from(_: std::io::Error) -> Self {
-        APIError::Io("IO error".to_string())
+    fn from(err: std::io::Error) -> Self {
+        APIError::Io(err.to_string())
+    }
+}
+
+impl From<crate::link::LinkError> for APIError {
+    fn from(err: crate::link::LinkError) -> Self {
+        APIError::Io(err.to_string())
     }
 }
 
@@ -63,116 +91,189 @@ impl From<serde_json::Error> for APIError {
     }
 }
 
-impl From<crate::hash::EldenError> for APIError {
+impl From<EldenError> for APIError {
     fn from(err: crate::hash::EldenError) -> Self {
-        APIError::EldenError(err.to_string())
+        match err {
+            EldenError::Interrupted => APIError::Interrupted,
+            err => APIError::EldenError(err.to_string()),
+        }
+    }
+}
+
+impl From<CivitAiError> for APIError {
+    fn from(err: CivitAiError) -> Self {
+        APIError::CivitAi(err)
+    }
+}
+
+impl From<crate::safetensors::SafetensorsError> for APIError {
+    fn from(err: crate::safetensors::SafetensorsError) -> Self {
+        APIError::Safetensors(err.to_string())
+    }
+}
+
+impl From<crate::sidecar::SidecarError> for APIError {
+    fn from(err: crate::sidecar::SidecarError) -> Self {
+        APIError::Sidecar(err.to_string())
+    }
+}
+
+impl From<crate::store::StoreError> for APIError {
+    fn from(err: crate::store::StoreError) -> Self {
+        APIError::Store(err.to_string())
+    }
+}
+
+impl From<crate::template::TemplateError> for APIError {
+    fn from(err: crate::template::TemplateError) -> Self {
+        APIError::Template(err.to_string())
     }
 }
 
-impl From<crate::civitai::CivitAiError> for APIError {
-    fn from(err: crate::civitai::CivitAiError) -> Self {
-        APIError::CivitAiError(err.to_string())
+impl From<Interrupted> for APIError {
+    fn from(_: Interrupted) -> Self {
+        APIError::Interrupted
     }
 }
 
 impl std::error::Error for APIError {}
 
+impl APIError {
+    /// Whether CivitAI is reachable but could not answer right now, so the lookup is worth
+    /// repeating in a later run.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            APIError::CivitAi(CivitAiError::RateLimited(_)) | APIError::CivitAi(CivitAiError::Server(_))
+        )
+    }
+}
+
 type Result<T> = std::result::Result<T, APIError>;
 
-pub fn lookup_cached_model_hash<P: AsRef<Path>>(model: P, cache_json_path: P) -> Result<String> {
-    let model_path_string = model.as_ref().to_string_lossy().to_string();
-    let cache_path = cache_json_path.as_ref().to_path_buf();
-    let cache_file = OpenOptions::new().read(true).open(cache_path)?;
-    debug!("Looking for cached hash for {}", model_path_string);
+/// Extensions of the files treated as models.
+pub const MODEL_EXTENSIONS: [&str; 5] = ["safetensors", "ckpt", "pt", "pth", "bin"];
 
-    let data: HashMap<String, String> = {
-        let reader = BufReader::new(&cache_file);
-        serde_json::from_reader(reader).unwrap_or_default()
-    };
+pub fn is_model_file(path: &Path) -> bool {
+    let extension = path.extension().unwrap_or_default().to_str().unwrap_or_default();
+    MODEL_EXTENSIONS.contains(&extension)
+}
 
-    let result = data.get(&model_path_string);
+/// The SHA256 of a model, calculated only when the store has no valid hash for it.
+pub fn get_model_hash<P: AsRef<Path>>(model: P, store: &mut Store) -> Result<String> {
+    let model_path = model.as_ref();
 
-    match result {
-        Some(hash) => Ok(hash.to_string()),
-        None => Err(APIError::ModelNotFound(
-            "Model not found in cache".to_string(),
-        )),
+    if let Some(hash) = store.lookup_hash(model_path)? {
+        debug!("Using cached hash for {}", model_path.display());
+        return Ok(hash);
     }
+
+    Ok(get_model_hashes(model_path, store)?.sha256)
 }
 
-pub fn cache_model_hash<P: AsRef<Path>>(hash: &str, model: P, json_path: P) -> Result<()> {
-    let model_path = model.as_ref().to_path_buf();
-    let model_path_string = model_path.to_string_lossy().to_string();
-    let cache_path = json_path.as_ref().to_path_buf();
+/// Every hash CivitAI knows of a model, calculated only when the store has no valid hashes for it.
+pub fn get_model_hashes<P: AsRef<Path>>(model: P, store: &mut Store) -> Result<ModelHashes> {
+    let model_path = model.as_ref();
 
-    let mut cache_file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .truncate(false)
-        .open(cache_path)?;
+    if let Some(hashes) = store.lookup_hashes(model_path)? {
+        if hashes.is_complete() {
+            debug!("Using cached hash for {}", model_path.display());
+            return Ok(hashes);
+        }
+        debug!("Only the SHA256 of {} is cached", model_path.display());
+    }
 
-    let mut data: HashMap<String, String> = {
-        let reader = BufReader::new(&cache_file);
-        serde_json::from_reader(reader).unwrap_or_default()
-    };
+    info!("Calculating hash for {}", model_path.display());
+    let hashes = EldenRing::hashes_from_file(model_path)?;
+    store.insert_hashes(model_path, &hashes)?;
+    store.checkpoint()?;
+    Ok(hashes)
+}
 
-    if let Entry::Vacant(entry) = data.entry(model_path_string.clone()) {
-        debug!("Caching hash for {}", &model_path_string);
-        entry.insert(hash.to_string());
+/// Hashes the models the store has no valid hash for in parallel, so the lookups that follow are
+/// answered from the store. With `complete`, models of which only the SHA256 is known are hashed
+/// again as well.
+pub fn hash_models(models: &[PathBuf], store: &mut Store, options: HashOptions, complete: bool) -> Result<()> {
+    let mut missing = vec![];
+    for model in models {
+        match store.lookup_hashes(model)? {
+            Some(hashes) if hashes.is_complete() || !complete => (),
+            _ => missing.push(model.clone()),
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
     }
 
-    cache_file.set_len(0)?;
-    cache_file.seek(SeekFrom::Start(0))?;
-
-    let writer = BufWriter::new(&cache_file);
-    serde_json::to_writer_pretty(writer, &data)?;
+    info!("Calculating hashes of {} models with {} jobs", missing.len(), options.jobs);
+    hash::hash_files(&missing, options, |model, result| {
+        let stored = match result {
+            Ok(hashes) => store.insert_hashes(model, &hashes).and_then(|_| store.checkpoint()),
+            Err(EldenError::Interrupted) => return,
+            Err(err) => {
+                error!("Error hashing {}: {}", model.display(), err);
+                return;
+            }
+        };
+        if let Err(err) = stored {
+            error!("Error storing hash of {}: {}", model.display(), err);
+        }
+    });
 
-    Ok(())
+    Ok(interrupt::check()?)
 }
 
-pub fn get_model_info<P: AsRef<Path>>(model: P, cache_json_path: Option<P>) -> Result<ModelInfo> {
-    let model_path = model.as_ref().to_path_buf();
-    let cache_path = match cache_json_path {
-        Some(path) => path.as_ref().to_path_buf(),
-        None => PathBuf::from("cache.json"),
-    };
+pub fn get_model_info<P: AsRef<Path>>(model: P, store: &mut Store, client: &CivitAiClient) -> Result<ModelInfo> {
+    let model_path = model.as_ref();
     debug!("Getting model info for {}", model_path.display());
 
-    let hash = match lookup_cached_model_hash(&model_path, &cache_path) {
-        Ok(hash) => {
-            debug!("Using cached hash for {}", model_path.display());
-            hash
-        }
-        Err(_) => {
-            info!("Calculating hash for {}", model_path.display());
-            let hash = EldenRing::from_file(&model)?;
-            cache_model_hash(&hash, &model_path, &cache_path)?;
-            hash
-        }
-    };
+    let hash = get_model_hash(model_path, store)?;
+    lookup_model_info(&hash, store, client)
+}
 
-    let model_info = query_model_info(&hash)?;
+/// The CivitAI information of the model with the given SHA256, from the store while the answer
+/// is recent enough.
+pub fn lookup_model_info(hash: &str, store: &mut Store, client: &CivitAiClient) -> Result<ModelInfo> {
+    if let Some(lookup) = store.civitai_lookup(hash)
+        && lookup.age() < client.cache_ttl(lookup.info.is_some())
+    {
+        match &lookup.info {
+            Some(info) => match serde_json::from_value(info.clone()) {
+                Ok(model_info) => {
+                    debug!("Using cached CivitAI info for {}", hash);
+                    return Ok(model_info);
+                }
+                Err(err) => debug!("Cached CivitAI info for {} is outdated: {}", hash, err),
+            },
+            None => {
+                debug!("{} was not found on CivitAI before", hash);
+                return Err(CivitAiError::NotFound(format!("no model with hash {}", hash)).into());
+            }
+        }
+    }
 
-    Ok(model_info)
+    match client.query_model_info(hash) {
+        Ok(model_info) => {
+            store.record_civitai_lookup(hash, Some(&model_info))?;
+            Ok(model_info)
+        }
+        Err(err) => {
+            if let CivitAiError::NotFound(_) = err {
+                store.record_civitai_lookup(hash, None)?;
+            }
+            Err(err.into())
+        }
+    }
 }
 
-pub fn move_orphan_model<P: AsRef<Path>>(orphan_model: P, destination: P, model_type: ModelType, base_model: &str) -> Result<()> {
+/// Moves an orphan and its sidecar files to `new_path` and returns it.
+pub fn move_orphan_model<P: AsRef<Path>>(orphan_model: P, new_path: &Path, plan: &mut Plan) -> Result<PathBuf> {
     let orphan_model_path = orphan_model.as_ref().to_path_buf();
-    let destination_path = destination.as_ref().to_path_buf();
-    let model_type_name = model_type.general_directory();
-    let base_model_name = base_model.to_lowercase();
-    let Some(file_name) = orphan_model_path.file_name() else {
-        return Err("Error getting file name".into());
+    let new_path = new_path.to_path_buf();
+    let Some(new_parent) = new_path.parent() else {
+        return Err("Error getting parent directory".into());
     };
 
-    let new_path = destination_path
-        .join(model_type_name)
-        .join(base_model_name)
-        .join(file_name);
-
-    let new_parent = new_path.parent().unwrap_or(&destination_path);
-
     info!(
         "Moving orphan model {} to {}",
         orphan_model_path.display(),
@@ -181,101 +282,515 @@ pub fn move_orphan_model<P: AsRef<Path>>(orphan_model: P, destination: P, model_
 
     if !new_parent.exists() {
         debug!("Creating directory {}", new_parent.display());
-        std::fs::create_dir_all(new_parent)?;
+        plan.create_dir_all(new_parent)?;
     }
 
-    std::fs::rename(&orphan_model_path, &new_path)?;
-    Ok(())
+    sidecar::move_sidecars(&orphan_model_path, &new_path, plan)?;
+    plan.rename(&orphan_model_path, &new_path)?;
+    Ok(new_path)
 }
 
-pub fn get_orphan_models<P: AsRef<Path>>(root: P) -> Result<Vec<PathBuf>> {
-    let root_path = root.as_ref().to_path_buf();
-    let Ok(read_dir) = root_path.read_dir() else {
-        return Err("Error reading directory".into());
-    };
+/// Models waiting to be sorted, in the root of the general directory and the inboxes.
+pub fn get_orphan_models<P: AsRef<Path>>(root: P, inbox: &InboxConfig) -> Result<Vec<PathBuf>> {
+    Ok(inbox::find_orphans(root.as_ref(), inbox)?)
+}
 
-    let mut dir_entries: Vec<DirEntry> = vec![];
+/// Like `get_orphan_models`, but also waits to leave out files that are still being written, for
+/// moving the orphans.
+pub fn get_settled_orphan_models<P: AsRef<Path>>(root: P, inbox: &InboxConfig) -> Result<Vec<PathBuf>> {
+    let orphans = get_orphan_models(root, inbox)?;
+    Ok(inbox::skip_growing(orphans, inbox.growth_check_ms))
+}
 
-    for entry in read_dir {
-        let Ok(entry) = entry else {
-            continue;
-        };
-        dir_entries.push(entry);
-    }
-
-    let orphan_model_entries: Vec<&DirEntry> = dir_entries
-        .iter()
-        .filter(|dir_entry| {
-            let allowed_extensions = ["safetensors", "ckpt", "pt", "pth", "bin"];
-            let path = dir_entry.path();
-            if !path.is_dir() {
-                allowed_extensions.contains(
-                    &path
-                        .extension()
-                        .unwrap_or_default()
-                        .to_str()
-                        .unwrap_or_default(),
-                )
-            } else {
-                false
-            }
-        })
+/// Every model file in the general directory, sorted or not.
+pub fn get_library_models<P: AsRef<Path>>(root: P) -> Result<Vec<PathBuf>> {
+    let mut models: Vec<PathBuf> = link::collect_files(root.as_ref())?
+        .into_iter()
+        .filter(|path| is_model_file(path))
         .collect();
+    models.sort();
+    Ok(models)
+}
 
-    let orphan_model_paths: Vec<PathBuf> = orphan_model_entries
-        .iter()
-        .map(|path| path.path().to_path_buf())
-        .collect();
+/// Infers the model type and base model from the safetensors header, if the file has one.
+pub fn detect_model<P: AsRef<Path>>(model: P) -> Result<(ModelType, String)> {
+    let model_path = model.as_ref();
+    let is_safetensors = model_path.extension().is_some_and(|ext| ext == "safetensors");
+    if !is_safetensors {
+        return Err(APIError::ModelNotFound(format!(
+            "{} is not a safetensors file",
+            model_path.display()
+        )));
+    }
+
+    match safetensors::detect_from_file(model_path)? {
+        Some(detection) => Ok((detection.model_type, detection.base_model)),
+        None => Err(APIError::ModelNotFound(format!(
+            "Could not detect model type of {} from its header",
+            model_path.display()
+        ))),
+    }
+}
+
+/// What `classify_model` found out about a model.
+#[derive(Debug)]
+pub struct Classification {
+    pub model_type: ModelType,
+    pub base_model: String,
+    /// The CivitAI information, if the model was identified there.
+    pub info: Option<ModelInfo>,
+}
+
+impl From<(ModelType, String)> for Classification {
+    fn from((model_type, base_model): (ModelType, String)) -> Self {
+        Self {
+            model_type,
+            base_model,
+            info: None,
+        }
+    }
+}
 
-    Ok(orphan_model_paths)
-}
-
-pub fn sort_models<P: AsRef<Path>>(root: P) -> Result<()> {
-    let root_path = root.as_ref().to_path_buf();
-    let orphan_models = get_orphan_models(&root_path)?;
-    orphan_models.iter().for_each(
-        |path| match get_model_info(path, Some(&root_path.join("orphan_cache.json"))) {
-            Ok(info) => {
-                let model_type = info.model_info.model_type;
-                let base_model = info.base_model.unwrap_or("Other".to_string());
-                match move_orphan_model(
-                    path.to_path_buf(),
-                    root_path.clone(),
-                    model_type,
-                    &base_model,
-                ) {
-                    Ok(_) => (),
-                    Err(err) => error!("Error moving orphan model: {}", err),
+impl From<ModelInfo> for Classification {
+    fn from(info: ModelInfo) -> Self {
+        Self {
+            model_type: info.model_info.model_type.clone(),
+            base_model: info.base_model.clone().unwrap_or("Other".to_string()),
+            info: Some(info),
+        }
+    }
+}
+
+/// Finds the model type and base model of a model, asking CivitAI and reading the safetensors
+/// header in the given order.
+pub fn classify_model<P: AsRef<Path>>(model: P, store: &mut Store, client: &CivitAiClient, detection: DetectionOrder) -> Result<Classification> {
+    let model_path = model.as_ref();
+    let mut from_civitai = || get_model_info(model_path, store, client).map(Classification::from);
+    let from_header = || detect_model(model_path).map(Classification::from);
+
+    match detection {
+        // A busy CivitAI is not a reason to settle for the header, the model is retried later
+        DetectionOrder::CivitaiFirst => from_civitai().or_else(|err| {
+            if err.is_transient() {
+                return Err(err);
+            }
+            debug!("{}, falling back to the safetensors header", err);
+            from_header()
+        }),
+        DetectionOrder::HeaderFirst => from_header().or_else(|err| {
+            debug!("{}, falling back to CivitAI", err);
+            from_civitai()
+        }),
+        DetectionOrder::HeaderOnly => from_header(),
+    }
+}
+
+/// Moves orphan models into the general directory, to where `config.destination` puts them.
+pub fn sort_models(orphan_models: &[PathBuf], models_structure: &FolderStructure, store: &mut Store, client: &CivitAiClient, config: &SortConfig, plan: &mut Plan) -> Result<()> {
+    let mut deferred = 0;
+    for (index, path) in orphan_models.iter().enumerate() {
+        interrupt::check()?;
+        let sorted = classify_model(path, store, client, config.detection).and_then(|classification| {
+            let destination = sort_destination(path, &classification, models_structure, store, client, config)?;
+            Ok((classification, destination))
+        });
+        match sorted {
+            Ok((classification, destination)) => {
+                if let Err(err) = sort_model(path, destination, &classification, store, client, config, plan) {
+                    error!("Error moving orphan model: {}", err);
                 }
             }
+            // Keep going after server errors, but stop asking once CivitAI is still rate
+            // limiting after all retries
+            Err(APIError::CivitAi(CivitAiError::RateLimited(retry_after))) => {
+                let retry_after = retry_after
+                    .map(|delay| format!(", retry after {}s", delay.as_secs()))
+                    .unwrap_or_default();
+                warn!("CivitAI is rate limiting requests{}", retry_after);
+                deferred += orphan_models.len() - index;
+                break;
+            }
+            Err(err) if err.is_transient() => {
+                warn!("Leaving {} in place: {}", path.display(), err);
+                deferred += 1;
+            }
             Err(err) => error!("Error getting model info: {}", err),
-        },
-    );
+        }
+    }
+
+    if deferred > 0 {
+        warn!("{} models were not sorted, run sort again later", deferred);
+    }
 
     Ok(())
 }
 
-pub fn process_comfyui(models_structure: &FolderStructure, config: &Option<Config>, comfyui_path: Option<PathBuf>) -> Result<()> {
-    if let Some(path) = comfyui_path {
-        let comfyui_structure: FolderStructure = match config {
-            Some(config) => config.clone().comfyui.try_into()?,
-            None => ComfyUIConfig::new(path).try_into()?,
-        };
+/// Moves an orphan to `destination`, or next to it when that name is taken, and writes its
+/// CivitAI files.
+fn sort_model(model: &Path, destination: PathBuf, classification: &Classification, store: &mut Store, client: &CivitAiClient, config: &SortConfig, plan: &mut Plan) -> Result<()> {
+    let new_path = if destination == model {
+        debug!("{} is already in place", model.display());
+        destination
+    } else {
+        let destination = link::free_path(&destination, plan);
+        let hashes = store.lookup_hashes(model)?;
+        let new_path = move_orphan_model(model, &destination, plan)?;
+        // A move to another filesystem is a copy, which would not be found again by its inode
+        if let Some(hashes) = hashes
+            && !plan.is_dry_run()
+        {
+            store.forget_hashes(model);
+            store.insert_hashes(&new_path, &hashes)?;
+        }
+        new_path
+    };
+
+    if let Some(info) = &classification.info {
+        write_civitai_files(&new_path, info, client, config, false, plan);
+    }
+    Ok(())
+}
+
+/// The name `rename` gives a model found on CivitAI, `None` to keep its name. The extension is
+/// always kept.
+fn canonical_file_name(model: &Path, info: &ModelInfo, sha256: &str, rename: RenameMode) -> Option<String> {
+    let stem = match rename {
+        RenameMode::Keep => return None,
+        RenameMode::ModelVersion => {
+            let model_name = info.model_info.name.as_deref()?;
+            match info.name.as_deref() {
+                Some(version_name) => format!("{} {}", model_name, version_name),
+                None => model_name.to_string(),
+            }
+        }
+        RenameMode::CivitaiFile => {
+            let file = info.file_by_hash(sha256).or_else(|| info.primary_file())?;
+            let name = Path::new(file.name.as_deref()?);
+            name.file_stem()?.to_string_lossy().to_string()
+        }
+    };
+
+    let stem = template::sanitize(&stem);
+    match model.extension() {
+        Some(extension) => Some(format!("{}.{}", stem, extension.to_string_lossy())),
+        None => Some(stem),
+    }
+}
+
+/// Where `sort_models` moves `model`, `config.destination` rendered for its classification.
+fn sort_destination(model: &Path, classification: &Classification, models_structure: &FolderStructure, store: &mut Store, client: &CivitAiClient, config: &SortConfig) -> Result<PathBuf> {
+    let category = classification.model_type.category().unwrap_or_else(|| {
+        info!("{} has no category, using {}", classification.model_type, config.fallback_directory);
+        &config.fallback_directory
+    });
+    let directory = models_structure.directory(category);
+    let directory = directory.strip_prefix(&models_structure.root).unwrap_or(&directory);
+
+    let mut values = BTreeMap::new();
+    values.insert("type", directory.to_string_lossy().to_string());
+    values.insert("model_type", classification.model_type.to_string());
+    values.insert("base_model", classification.base_model.clone());
+    if let Some(file_name) = model.file_name() {
+        values.insert("file_name", file_name.to_string_lossy().to_string());
+    }
+    if let Some(info) = &classification.info {
+        if config.rename != RenameMode::Keep
+            && let Some(sha256) = store.lookup_hash(model)?
+            && let Some(file_name) = canonical_file_name(model, info, &sha256, config.rename)
+        {
+            values.insert("file_name", file_name);
+        }
+        values.insert("model_id", info.model_id.to_string());
+        values.insert("version_id", info.id.to_string());
+        values.insert("nsfw", if info.model_info.nsfw { "nsfw" } else { "sfw" }.to_string());
+        let optional = [
+            ("base_model_type", &info.base_model_type),
+            ("model_name", &info.model_info.name),
+            ("version_name", &info.name),
+        ];
+        for (name, value) in optional {
+            if let Some(value) = value {
+                values.insert(name, value.clone());
+            }
+        }
+        // Costs a request per model, so only asked for when it is used
+        if template::placeholders(&config.destination)?.contains(&"creator")
+            && let Some(creator) = lookup_creator(info.model_id, store, client)?
+        {
+            values.insert("creator", creator);
+        }
+    }
+
+    Ok(models_structure.root.join(template::render(&config.destination, &values)?))
+}
+
+/// The CivitAI username of whoever published the model with the given id, from the store while
+/// the answer is recent enough. `None` when it cannot be found out right now.
+pub fn lookup_creator(model_id: u64, store: &mut Store, client: &CivitAiClient) -> Result<Option<String>> {
+    if let Some(lookup) = store.civitai_model(model_id)
+        && lookup.age() < client.cache_ttl(lookup.info.is_some())
+    {
+        let model = lookup.info.clone().map(serde_json::from_value::<Model>);
+        match model {
+            Some(Ok(model)) => return Ok(model.creator.and_then(|creator| creator.username)),
+            Some(Err(err)) => debug!("Cached CivitAI model {} is outdated: {}", model_id, err),
+            None => return Ok(None),
+        }
+    }
+
+    match client.query_model(model_id) {
+        Ok(model) => {
+            store.record_civitai_model(model_id, Some(&model))?;
+            Ok(model.creator.and_then(|creator| creator.username))
+        }
+        Err(CivitAiError::NotFound(_)) => {
+            store.record_civitai_model(model_id, None)?;
+            Ok(None)
+        }
+        Err(CivitAiError::Offline) => Ok(None),
+        Err(err) => {
+            let err = APIError::from(err);
+            if err.is_transient() {
+                return Err(err);
+            }
+            warn!("Could not look up the creator of model {}: {}", model_id, err);
+            Ok(None)
+        }
+    }
+}
+
+/// Writes the sidecar files and preview enabled in `config`, failures are logged since the model
+/// itself is fine without them.
+fn write_civitai_files(model: &Path, info: &ModelInfo, client: &CivitAiClient, config: &SortConfig, overwrite: bool, plan: &mut Plan) {
+    if config.sidecars
+        && let Err(err) = sidecar::write_sidecars(model, info, overwrite, plan)
+    {
+        error!("Error writing metadata for {}: {}", model.display(), err);
+    }
+    if config.previews
+        && !client.is_offline()
+        && let Err(err) = sidecar::write_preview(model, info, client, config.max_nsfw_level, overwrite, plan)
+    {
+        error!("Error writing preview for {}: {}", model.display(), err);
+    }
+}
 
-        models_structure.soft_link_to(&comfyui_structure)?;
+/// Writes the CivitAI sidecar files of models that are found there, skipping the others.
+pub fn write_model_sidecars(models: &[PathBuf], store: &mut Store, client: &CivitAiClient, config: &SortConfig, overwrite: bool, plan: &mut Plan) -> Result<()> {
+    for model in models {
+        interrupt::check()?;
+        match get_model_info(model, store, client) {
+            Ok(info) => write_civitai_files(model, &info, client, config, overwrite, plan),
+            Err(APIError::CivitAi(CivitAiError::NotFound(_))) => {
+                info!("{} is not on CivitAI, skipping", model.display())
+            }
+            Err(err) => error!("Error getting model info for {}: {}", model.display(), err),
+        }
     }
 
     Ok(())
 }
 
-pub fn process_webui(models_structure: &FolderStructure, config: &Option<Config>, webui_path: Option<PathBuf>) -> Result<()> {
-    if let Some(path) = webui_path {
-        let webui_structure: FolderStructure = match config {
-            Some(config) => config.clone().webui.try_into()?,
-            None => WebUIConfig::new(path).try_into()?,
+/// Something wrong with a library file found by `verify_library`.
+#[derive(Debug, PartialEq)]
+pub enum VerifyIssue {
+    /// The store has a hash for the file, but it is gone.
+    Missing,
+    Unreadable(String),
+    /// The contents changed although size and modification time did not, typically bit rot.
+    Corrupted { expected: String, actual: String },
+    /// The file differs from the one CivitAI lists for the model.
+    CivitAiMismatch { expected: String, actual: String },
+    /// The file is smaller than the one CivitAI lists, typically an interrupted download.
+    Truncated { expected: u64, actual: u64 },
+}
+
+impl std::fmt::Display for VerifyIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyIssue::Missing => write!(f, "missing"),
+            VerifyIssue::Unreadable(msg) => write!(f, "unreadable: {}", msg),
+            VerifyIssue::Corrupted { expected, actual } => {
+                write!(f, "corrupted, SHA256 is {} but was {} when last hashed", actual, expected)
+            }
+            VerifyIssue::CivitAiMismatch { expected, actual } => {
+                write!(f, "SHA256 is {} but CivitAI lists {}", actual, expected)
+            }
+            VerifyIssue::Truncated { expected, actual } => {
+                write!(f, "truncated, {} bytes but CivitAI lists {}", actual, expected)
+            }
+        }
+    }
+}
+
+/// Outcome of `verify_library`.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub verified: usize,
+    pub issues: Vec<(PathBuf, VerifyIssue)>,
+}
+
+/// Hashes every model of the library again and compares it with the hash stored while it was
+/// unchanged and with the file CivitAI lists for it. Files the store knows of but that are gone
+/// are reported as well, and forgotten with `prune`.
+pub fn verify_library(models_structure: &FolderStructure, store: &mut Store, client: &CivitAiClient, options: HashOptions, prune: bool) -> Result<VerifyReport> {
+    let mut models = vec![];
+    for directory in models_structure.directories() {
+        if directory.is_dir() {
+            models.extend(get_library_models(directory)?);
+        }
+    }
+
+    // Taken before hashing, moved files are found by inode so they are not reported missing
+    let mut known = std::collections::BTreeMap::new();
+    for model in &models {
+        if let Some(hashes) = store.lookup_hashes(model)? {
+            known.insert(model.clone(), hashes);
+        }
+    }
+
+    let mut report = VerifyReport::default();
+    for file in store.library_files() {
+        if !file.exists() {
+            if prune {
+                store.forget_hashes(&file);
+            }
+            report.issues.push((file, VerifyIssue::Missing));
+        }
+    }
+
+    let mut results = vec![];
+    hash::hash_files(&models, options, |model, result| results.push((model.to_path_buf(), result)));
+    interrupt::check()?;
+    for (model, result) in results {
+        report.verified += 1;
+        let hashes = match result {
+            Ok(hashes) => hashes,
+            Err(err) => {
+                report.issues.push((model, VerifyIssue::Unreadable(err.to_string())));
+                continue;
+            }
         };
 
-        models_structure.soft_link_to(&webui_structure)?;
+        let expected = known.get(&model).map_or(&hashes.sha256, |known| &known.sha256);
+        if *expected != hashes.sha256 {
+            report.issues.push((
+                model.clone(),
+                VerifyIssue::Corrupted {
+                    expected: expected.clone(),
+                    actual: hashes.sha256.clone(),
+                },
+            ));
+        } else {
+            store.insert_hashes(&model, &hashes)?;
+        }
+
+        if let Some(issue) = verify_against_civitai(&model, expected, &hashes, store, client)? {
+            report.issues.push((model, issue));
+        }
+    }
+    store.save()?;
+
+    report.issues.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(report)
+}
+
+/// Compares a model with the file CivitAI lists under its expected hash, or in its
+/// `.civitai.info` when CivitAI does not know that hash, as for a truncated download.
+fn verify_against_civitai(model: &Path, expected: &str, hashes: &ModelHashes, store: &mut Store, client: &CivitAiClient) -> Result<Option<VerifyIssue>> {
+    let info = match lookup_model_info(expected, store, client) {
+        Ok(info) => info,
+        Err(err) => {
+            match err {
+                APIError::CivitAi(CivitAiError::NotFound(_) | CivitAiError::Offline) => (),
+                err => warn!("Not comparing {} with CivitAI: {}", model.display(), err),
+            }
+            match sidecar::read_civitai_info(model) {
+                Ok(info) => info,
+                Err(err) => {
+                    debug!("No CivitAI info for {}: {}", model.display(), err);
+                    return Ok(None);
+                }
+            }
+        }
+    };
+
+    let file_name = model.file_name().and_then(|name| name.to_str());
+    let file = info
+        .file_by_hash(expected)
+        .or_else(|| info.files.iter().find(|file| file.name.as_deref() == file_name))
+        .or_else(|| info.files.first().filter(|_| info.files.len() == 1));
+    let Some(file) = file else {
+        debug!("CivitAI lists no file matching {}", model.display());
+        return Ok(None);
+    };
+
+    let size = std::fs::metadata(model)?.len();
+    let expected_size = (file.size_kb * 1024.0).round() as u64;
+    if size < expected_size {
+        return Ok(Some(VerifyIssue::Truncated {
+            expected: expected_size,
+            actual: size,
+        }));
+    }
+    match file.hashes.sha256.as_deref() {
+        Some(sha256) if !sha256.eq_ignore_ascii_case(&hashes.sha256) => Ok(Some(VerifyIssue::CivitAiMismatch {
+            expected: sha256.to_uppercase(),
+            actual: hashes.sha256.clone(),
+        })),
+        _ => Ok(None),
+    }
+}
+
+pub fn print_status(name: &str, models_structure: &FolderStructure, frontend_structure: &FolderStructure) -> Result<()> {
+    println!("{}:", name);
+    for (from, to) in models_structure.pairs(frontend_structure) {
+        let state = link::link_state(from, to)?;
+        println!("  {:<50} {}", to.display(), state);
+    }
+
+    Ok(())
+}
+
+/// Remembers how each frontend directory was linked, unless the links were only planned.
+fn record_links(models_structure: &FolderStructure, frontend_structure: &FolderStructure, link_mode: LinkMode, store: &mut Store, plan: &Plan) {
+    if plan.is_dry_run() {
+        return;
+    }
+    for (from, to) in models_structure.pairs(frontend_structure) {
+        store.record_link(from, to, link_mode);
+    }
+}
+
+pub fn process_comfyui(models_structure: &FolderStructure, comfyui: Option<ComfyUIConfig>, policy: ReplacePolicy, store: &mut Store, plan: &mut Plan) -> Result<()> {
+    if let Some(comfyui) = comfyui {
+        let link_mode = comfyui.link_mode;
+        let comfyui_structure: FolderStructure = comfyui.try_into()?;
+        models_structure.link_to(&comfyui_structure, link_mode, policy, store, plan)?;
+        record_links(models_structure, &comfyui_structure, link_mode, store, plan);
+    }
+
+    Ok(())
+}
+
+pub fn process_webui(models_structure: &FolderStructure, webui: Option<WebUIConfig>, policy: ReplacePolicy, store: &mut Store, plan: &mut Plan) -> Result<()> {
+    if let Some(webui) = webui {
+        let link_mode = webui.link_mode;
+        let webui_structure: FolderStructure = webui.try_into()?;
+        models_structure.link_to(&webui_structure, link_mode, policy, store, plan)?;
+        record_links(models_structure, &webui_structure, link_mode, store, plan);
+    }
+
+    Ok(())
+}
+
+/// Detaches a frontend from the general directory and forgets how it was linked.
+pub fn process_unlink(models_structure: &FolderStructure, frontend_structure: &FolderStructure, restore: bool, store: &mut Store, plan: &mut Plan) -> Result<()> {
+    models_structure.unlink_from(frontend_structure, restore, plan)?;
+    if !plan.is_dry_run() {
+        for (_, to) in models_structure.pairs(frontend_structure) {
+            store.remove_link(to);
+        }
     }
 
     Ok(())
@@ -1,14 +1,40 @@
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use log::debug;
+use log::warn;
 
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json::Value;
 
-pub const API_URL: &str = "https://civitai.com/api/v1/model-versions/by-hash/";
+use crate::configuration::CivitAiConfig;
+
+pub const DEFAULT_API_URL: &str = "https://civitai.com/api/v1";
+
+/// Environment variable overriding the API base URL, e.g. to point at a local mock server.
+pub const API_URL_ENV: &str = "CIVITAI_API_URL";
+
+/// Environment variable holding the API key, takes precedence over the config file.
+pub const API_KEY_ENV: &str = "CIVITAI_API_KEY";
+
+/// Retries are capped at this delay, a longer `Retry-After` gives up instead of blocking the run.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
 
 #[derive(Debug)]
 pub enum CivitAiError {
+    NotFound(String),
+    Unauthorized(u16),
+    RateLimited(Option<Duration>),
+    Server(u16),
+    Connection(String),
+    Offline,
     Reqwest(String),
     Unspecified(String),
 }
@@ -16,6 +42,17 @@ pub enum CivitAiError {
 impl std::fmt::Display for CivitAiError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            CivitAiError::NotFound(s) => write!(f, "Not found: {}", s),
+            CivitAiError::Unauthorized(status) => {
+                write!(f, "Unauthorized ({}), check the API key", status)
+            }
+            CivitAiError::RateLimited(Some(retry_after)) => {
+                write!(f, "Rate limited, retry after {}s", retry_after.as_secs())
+            }
+            CivitAiError::RateLimited(None) => write!(f, "Rate limited"),
+            CivitAiError::Server(status) => write!(f, "Server error ({})", status),
+            CivitAiError::Connection(s) => write!(f, "Connection: {}", s),
+            CivitAiError::Offline => write!(f, "Offline mode, CivitAI was not asked"),
             CivitAiError::Reqwest(s) => write!(f, "Reqwest: {}", s),
             CivitAiError::Unspecified(s) => write!(f, "Unspecified: {}", s),
         }
@@ -24,6 +61,16 @@ impl std::fmt::Display for CivitAiError {
 
 impl std::error::Error for CivitAiError {}
 
+impl CivitAiError {
+    /// Whether the request may succeed when it is repeated later.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            CivitAiError::RateLimited(_) | CivitAiError::Server(_) | CivitAiError::Connection(_)
+        )
+    }
+}
+
 impl From<&str> for CivitAiError {
     fn from(s: &str) -> Self {
         CivitAiError::Unspecified(s.to_string())
@@ -44,8 +91,47 @@ impl From<reqwest::Error> for CivitAiError {
 
 type Result<T> = std::result::Result<T, CivitAiError>;
 
+/// CivitAI API token. It is only ever exposed to build the authorization header, `Debug` and
+/// `Display` redact it so it cannot leak into logs.
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct ApiKey(String);
+
+impl ApiKey {
+    pub fn new<S: Into<String>>(key: S) -> Self {
+        Self(key.into().trim().to_string())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ApiKey(<redacted>)")
+    }
+}
+
+impl fmt::Display for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+/// Default credentials file, `$XDG_CONFIG_HOME/model_sync/civitai_api_key` or the platform
+/// equivalent.
+pub fn default_api_key_file() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("model_sync").join("civitai_api_key"))
+}
+
+/// A model version as CivitAI returns it. Fields added to the API later are kept in `extra`, so
+/// they survive in the stored answers and `.civitai.info` files.
 #[derive(Serialize, Deserialize, Debug)]
-#[serde(deny_unknown_fields)]
 pub struct ModelInfo {
     pub id: u64,
     #[serde(rename = "modelId")]
@@ -85,6 +171,22 @@ pub struct ModelInfo {
     pub images: Vec<Image>,
     #[serde(rename = "downloadUrl")]
     pub download_url: Option<String>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+impl ModelInfo {
+    /// The file of this version with the given SHA256.
+    pub fn file_by_hash(&self, sha256: &str) -> Option<&File> {
+        self.files
+            .iter()
+            .find(|file| file.hashes.sha256.as_deref().is_some_and(|hash| hash.eq_ignore_ascii_case(sha256)))
+    }
+
+    /// The file CivitAI offers for download by default.
+    pub fn primary_file(&self) -> Option<&File> {
+        self.files.iter().find(|file| file.primary)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -114,55 +216,87 @@ pub struct ModelData {
     pub poi: bool,
 }
 
+/// A model as CivitAI returns it from `/models/{id}`, reduced to what is used. Its versions are
+/// looked up by hash instead.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Model {
+    pub id: u64,
+    pub name: Option<String>,
+    pub creator: Option<Creator>,
+}
+
+/// The CivitAI user who published a model, missing for deleted accounts.
 #[derive(Serialize, Deserialize, Debug)]
+pub struct Creator {
+    pub username: Option<String>,
+}
+
+/// Model types as CivitAI names them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ModelType {
     Checkpoint,
-    Embedding,
+    TextualInversion,
+    Hypernetwork,
+    AestheticGradient,
     #[serde(rename = "LORA")]
     Lora,
+    LoCon,
+    DoRA,
     Controlnet,
     Upscaler,
+    MotionModule,
+    #[serde(rename = "VAE")]
     Vae,
+    Poses,
+    Wildcards,
+    Workflows,
+    /// CivitAI's own "Other" as well as types added after this release.
+    #[serde(untagged)]
+    Other(String),
 }
 
 impl std::fmt::Display for ModelType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ModelType::Checkpoint => write!(f, "Checkpoint"),
-            ModelType::Embedding => write!(f, "Embedding"),
+            ModelType::TextualInversion => write!(f, "Textual Inversion"),
+            ModelType::Hypernetwork => write!(f, "Hypernetwork"),
+            ModelType::AestheticGradient => write!(f, "Aesthetic Gradient"),
             ModelType::Lora => write!(f, "LoRA"),
+            ModelType::LoCon => write!(f, "LyCORIS"),
+            ModelType::DoRA => write!(f, "DoRA"),
             ModelType::Controlnet => write!(f, "Controlnet"),
             ModelType::Upscaler => write!(f, "Upscaler"),
+            ModelType::MotionModule => write!(f, "Motion Module"),
             ModelType::Vae => write!(f, "VAE"),
+            ModelType::Poses => write!(f, "Poses"),
+            ModelType::Wildcards => write!(f, "Wildcards"),
+            ModelType::Workflows => write!(f, "Workflows"),
+            ModelType::Other(name) => write!(f, "{}", name),
         }
     }
 }
 
 impl ModelType {
-    pub fn general_directory(&self) -> &str {
-        match self {
+    /// Category of the general directory models of this type are sorted into, `None` for types
+    /// that have none and go to the fallback directory.
+    pub fn category(&self) -> Option<&'static str> {
+        let category = match self {
             ModelType::Checkpoint => "checkpoints",
-            ModelType::Embedding => "embeddings",
-            ModelType::Lora => "loras",
+            ModelType::TextualInversion => "embeddings",
+            ModelType::Hypernetwork => "hypernetworks",
+            ModelType::AestheticGradient => "aesthetic_embeddings",
+            ModelType::Lora | ModelType::LoCon | ModelType::DoRA => "loras",
             ModelType::Controlnet => "controlnet",
             ModelType::Upscaler => "upscale_models",
+            ModelType::MotionModule => "animatediff_models",
             ModelType::Vae => "vae",
-        }
-    }
-
-    pub fn comfyui_directory(&self) -> &str {
-        Self::general_directory(self)
-    }
-
-    pub fn webui_directory(&self) -> &str {
-        match self {
-            ModelType::Checkpoint => "Stable-diffusion",
-            ModelType::Embedding => "embeddings",
-            ModelType::Lora => "Lora",
-            ModelType::Controlnet => "ControlNet",
-            ModelType::Upscaler => "ESRGAN",
-            ModelType::Vae => "VAE",
-        }
+            ModelType::Poses => "poses",
+            ModelType::Wildcards => "wildcards",
+            ModelType::Workflows => "workflows",
+            ModelType::Other(_) => return None,
+        };
+        Some(category)
     }
 }
 
@@ -438,18 +572,292 @@ impl fmt::Display for ImageMetadata {
     }
 }
 
-pub fn query_model_info(hash: &str) -> Result<ModelInfo> {
-    let url = format!("{}{}", API_URL, hash);
-    let Ok(resp) = reqwest::blocking::get(url) else {
-        return Err("Failed to query Civitai".into());
-    };
+/// Spaces out requests so they never exceed a rate, shared by all clones of a client.
+#[derive(Debug)]
+struct RateLimiter {
+    interval: Duration,
+    next_request: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(requests_per_second: f64) -> Self {
+        let interval = if requests_per_second > 0.0 {
+            Duration::from_secs_f64(1.0 / requests_per_second)
+        } else {
+            Duration::ZERO
+        };
+        Self {
+            interval,
+            next_request: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Blocks until the next request may be sent.
+    fn wait(&self) {
+        let mut next_request = self.next_request.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        if *next_request > now {
+            std::thread::sleep(*next_request - now);
+        }
+        *next_request = Instant::now() + self.interval;
+    }
+
+    /// Holds back every request for at least `delay`, e.g. after the server asked to slow down.
+    fn pause(&self, delay: Duration) {
+        let mut next_request = self.next_request.lock().unwrap_or_else(|e| e.into_inner());
+        *next_request = (*next_request).max(Instant::now() + delay);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CivitAiClient {
+    base_url: String,
+    api_key: Option<ApiKey>,
+    max_retries: u32,
+    retry_backoff: Duration,
+    rate_limiter: Arc<RateLimiter>,
+    cache_ttl: Duration,
+    not_found_ttl: Duration,
+    refresh: bool,
+    offline: bool,
+    http: reqwest::blocking::Client,
+}
+
+impl CivitAiClient {
+    pub fn new<S: Into<String>>(base_url: S) -> Self {
+        let defaults = CivitAiConfig::default();
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key: None,
+            max_retries: defaults.max_retries,
+            retry_backoff: Duration::from_millis(defaults.retry_backoff_ms),
+            rate_limiter: Arc::new(RateLimiter::new(defaults.requests_per_second)),
+            cache_ttl: Duration::from_secs(defaults.cache_ttl_hours * 3600),
+            not_found_ttl: Duration::from_secs(defaults.not_found_ttl_hours * 3600),
+            refresh: false,
+            offline: false,
+            http: reqwest::blocking::Client::new(),
+        }
+    }
+
+    pub fn with_api_key(mut self, api_key: Option<ApiKey>) -> Self {
+        self.api_key = api_key.filter(|key| !key.expose().is_empty());
+        self
+    }
+
+    pub fn with_retries(mut self, max_retries: u32, retry_backoff: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_backoff = retry_backoff;
+        self
+    }
+
+    pub fn with_rate_limit(mut self, requests_per_second: f64) -> Self {
+        self.rate_limiter = Arc::new(RateLimiter::new(requests_per_second));
+        self
+    }
+
+    pub fn with_cache_ttl(mut self, cache_ttl: Duration, not_found_ttl: Duration) -> Self {
+        self.cache_ttl = cache_ttl;
+        self.not_found_ttl = not_found_ttl;
+        self
+    }
+
+    /// Ignore cached answers and ask CivitAI again.
+    pub fn with_refresh(mut self, refresh: bool) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    /// Never send requests, only cached answers are used.
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// How long a cached answer stays valid, depending on whether the model was found. Offline
+    /// any answer is better than none, with `refresh` none is.
+    pub fn cache_ttl(&self, found: bool) -> Duration {
+        if self.offline {
+            Duration::MAX
+        } else if self.refresh {
+            Duration::ZERO
+        } else if found {
+            self.cache_ttl
+        } else {
+            self.not_found_ttl
+        }
+    }
+
+    /// Builds a client from the `[civitai]` config section. `CIVITAI_API_URL` and
+    /// `CIVITAI_API_KEY` take precedence, then `api_key`, then `api_key_file` or the default
+    /// credentials file.
+    pub fn from_config(config: &CivitAiConfig) -> Result<Self> {
+        let base_url = std::env::var(API_URL_ENV)
+            .ok()
+            .filter(|url| !url.is_empty())
+            .or_else(|| config.api_url.clone())
+            .unwrap_or(DEFAULT_API_URL.to_string());
+
+        let api_key = match std::env::var(API_KEY_ENV).ok().filter(|key| !key.is_empty()) {
+            Some(key) => {
+                debug!("Using CivitAI API key from {}", API_KEY_ENV);
+                Some(ApiKey::new(key))
+            }
+            None if config.api_key.is_some() => {
+                debug!("Using CivitAI API key from config");
+                config.api_key.clone()
+            }
+            None => match &config.api_key_file {
+                Some(path) => Some(read_api_key_file(path)?),
+                None => default_api_key_file()
+                    .filter(|path| path.is_file())
+                    .map(|path| read_api_key_file(&path))
+                    .transpose()?,
+            },
+        };
+
+        Ok(Self::new(base_url)
+            .with_api_key(api_key)
+            .with_retries(config.max_retries, Duration::from_millis(config.retry_backoff_ms))
+            .with_rate_limit(config.requests_per_second)
+            .with_cache_ttl(
+                Duration::from_secs(config.cache_ttl_hours * 3600),
+                Duration::from_secs(config.not_found_ttl_hours * 3600),
+            ))
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Starts a GET request, authenticated if asked to and an API key is configured. The key only
+    /// ever goes to the API host, never to hosts named in its responses.
+    fn get(&self, url: &str, authenticated: bool) -> reqwest::blocking::RequestBuilder {
+        let request = self.http.get(url);
+        match &self.api_key {
+            Some(api_key) if authenticated && self.is_api_host(url) => request.bearer_auth(api_key.expose()),
+            _ => request,
+        }
+    }
+
+    /// Whether `url` points to the same host and port as the API.
+    fn is_api_host(&self, url: &str) -> bool {
+        let (Ok(url), Ok(base_url)) = (reqwest::Url::parse(url), reqwest::Url::parse(&self.base_url)) else {
+            return false;
+        };
+        url.host_str() == base_url.host_str() && url.port_or_known_default() == base_url.port_or_known_default()
+    }
+
+    /// Sends a GET request within the rate limit, retrying transient failures with exponential
+    /// backoff or as long as the server asks for with `Retry-After`.
+    fn send(&self, url: &str, authenticated: bool) -> Result<reqwest::blocking::Response> {
+        if self.offline {
+            return Err(CivitAiError::Offline);
+        }
+
+        let mut attempt = 0;
+        loop {
+            self.rate_limiter.wait();
+            let err = match self.get(url, authenticated).send() {
+                Ok(resp) => match status_error(&resp) {
+                    None => return Ok(resp),
+                    Some(err) => err,
+                },
+                Err(e) => CivitAiError::Connection(e.to_string()),
+            };
+
+            if !err.is_transient() || attempt >= self.max_retries {
+                return Err(err);
+            }
+            let delay = match err {
+                CivitAiError::RateLimited(Some(retry_after)) => retry_after,
+                _ => self.retry_backoff.saturating_mul(2u32.saturating_pow(attempt)).min(MAX_RETRY_DELAY),
+            };
+            if delay > MAX_RETRY_DELAY {
+                return Err(err);
+            }
+
+            attempt += 1;
+            warn!(
+                "CivitAI request failed: {}, retrying in {:.1}s ({}/{})",
+                err,
+                delay.as_secs_f64(),
+                attempt,
+                self.max_retries
+            );
+            self.rate_limiter.pause(delay);
+        }
+    }
+
+    pub fn query_model_info(&self, hash: &str) -> Result<ModelInfo> {
+        let url = format!("{}/model-versions/by-hash/{}", self.base_url, hash);
+        let resp = self.send(&url, true).map_err(|err| match err {
+            CivitAiError::NotFound(_) => CivitAiError::NotFound(format!("no model with hash {}", hash)),
+            err => err,
+        })?;
 
-    if resp.status().is_success() {
         let data: ModelInfo = resp.json()?;
-        return Ok(data);
-    } else if resp.status().is_server_error() {
-        return Err(format!("Civitai Error: {}", resp.status()).into());
+        Ok(data)
     }
 
-    Err("Model not found".into())
+    pub fn query_model(&self, model_id: u64) -> Result<Model> {
+        let url = format!("{}/models/{}", self.base_url, model_id);
+        let resp = self.send(&url, true).map_err(|err| match err {
+            CivitAiError::NotFound(_) => CivitAiError::NotFound(format!("no model with id {}", model_id)),
+            err => err,
+        })?;
+
+        let data: Model = resp.json()?;
+        Ok(data)
+    }
+
+    /// Downloads a file linked from the API, such as a preview image. These are public, so the
+    /// request is never authenticated.
+    pub fn download(&self, url: &str) -> Result<Vec<u8>> {
+        let resp = self.send(url, false)?;
+        Ok(resp.bytes()?.to_vec())
+    }
+}
+
+/// Maps unsuccessful responses to the matching error.
+fn status_error(resp: &reqwest::blocking::Response) -> Option<CivitAiError> {
+    let status = resp.status();
+    match status.as_u16() {
+        _ if status.is_success() => None,
+        401 | 403 => Some(CivitAiError::Unauthorized(status.as_u16())),
+        404 => Some(CivitAiError::NotFound(resp.url().path().to_string())),
+        429 => Some(CivitAiError::RateLimited(retry_after(resp))),
+        _ if status.is_server_error() => Some(CivitAiError::Server(status.as_u16())),
+        _ => Some(CivitAiError::Unspecified(format!("Unexpected response {}", status))),
+    }
+}
+
+/// Reads a `Retry-After` header given in seconds, HTTP dates are left to the regular backoff.
+fn retry_after(resp: &reqwest::blocking::Response) -> Option<Duration> {
+    resp.headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+fn read_api_key_file(path: &std::path::Path) -> Result<ApiKey> {
+    debug!("Using CivitAI API key from {}", path.display());
+    match std::fs::read_to_string(path) {
+        Ok(key) => Ok(ApiKey::new(key)),
+        Err(e) => Err(format!("Couldn't read API key file {}: {}", path.display(), e).into()),
+    }
+}
+
+impl Default for CivitAiClient {
+    fn default() -> Self {
+        Self::new(DEFAULT_API_URL)
+    }
 }
@@ -1,76 +1,175 @@
+use std::collections::BTreeMap;
 use std::path::Path;
 use std::path::PathBuf;
 
+use glob::Pattern;
 use log::debug;
 use relative_path::RelativePath;
 use relative_path::RelativePathBuf;
 use serde::Deserialize;
+use serde::Deserializer;
 
+use crate::civitai::ApiKey;
 use crate::link;
+use crate::link::LinkMode;
+use crate::link::ReplacePolicy;
+use crate::plan::Plan;
+use crate::store::Store;
+use crate::template;
 
-#[derive(Debug, Deserialize, Clone)]
-pub struct RelativeFolderStructure {
-    pub checkpoints: RelativePathBuf,
-    pub loras: RelativePathBuf,
-    pub controlnet: RelativePathBuf,
-    pub upscale_models: RelativePathBuf,
-    pub vae: RelativePathBuf,
-    pub embeddings: RelativePathBuf,
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String),
+    Toml(String),
+    InboxOverlap(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(msg) => write!(f, "Config IO error: {}", msg),
+            ConfigError::Toml(msg) => write!(f, "Config parse error: {}", msg),
+            ConfigError::InboxOverlap(msg) => write!(f, "Inbox overlaps the general directory: {}", msg),
+        }
+    }
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(e: std::io::Error) -> Self {
+        ConfigError::Io(e.to_string())
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> Self {
+        ConfigError::Toml(e.to_string())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Directory of every model category relative to a base directory, keyed by category name. The
+/// names are those of the general directory, like `checkpoints` or `text_encoders`.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct RelativeFolderStructure(pub BTreeMap<String, RelativePathBuf>);
+
+impl RelativeFolderStructure {
+    fn from_pairs(pairs: &[(&str, &str)]) -> Self {
+        Self(
+            pairs
+                .iter()
+                .map(|(category, path)| (category.to_string(), RelativePath::new(path).to_relative_path_buf()))
+                .collect(),
+        )
+    }
+
+    /// Applies configured entries over these, an empty path removes the category.
+    fn merge(mut self, overrides: BTreeMap<String, RelativePathBuf>) -> Self {
+        for (category, path) in overrides {
+            if path.as_str().is_empty() {
+                self.0.remove(&category);
+            } else {
+                self.0.insert(category, path);
+            }
+        }
+        self
+    }
+}
+
+/// Reads a configured structure, keeping the defaults for categories it does not mention.
+fn merge_structure<'de, D: Deserializer<'de>>(deserializer: D, defaults: RelativeFolderStructure) -> Result<RelativeFolderStructure, D::Error> {
+    let overrides = BTreeMap::<String, RelativePathBuf>::deserialize(deserializer)?;
+    Ok(defaults.merge(overrides))
 }
 
 #[derive(Debug)]
 pub struct FolderStructure {
-    pub checkpoints: PathBuf,
-    pub loras: PathBuf,
-    pub controlnet: PathBuf,
-    pub upscale_models: PathBuf,
-    pub vae: PathBuf,
-    pub embeddings: PathBuf,
+    pub root: PathBuf,
+    pub categories: BTreeMap<String, PathBuf>,
 }
 
 impl FolderStructure {
     pub fn from_relative(base_path: PathBuf, relative_paths: RelativeFolderStructure) -> Self {
         Self {
-            checkpoints: relative_paths.checkpoints.to_logical_path(&base_path),
-            loras: relative_paths.loras.to_logical_path(&base_path),
-            controlnet: relative_paths.controlnet.to_logical_path(&base_path),
-            upscale_models: relative_paths.upscale_models.to_logical_path(&base_path),
-            vae: relative_paths.vae.to_logical_path(&base_path),
-            embeddings: relative_paths.embeddings.to_logical_path(&base_path),
+            categories: relative_paths
+                .0
+                .into_iter()
+                .map(|(category, path)| (category, path.to_logical_path(&base_path)))
+                .collect(),
+            root: base_path,
         }
     }
 
-    pub fn hard_link_to(&self, to: &Self) -> Result<(), std::io::Error> {
-        let paths = [
-            (&self.checkpoints, &to.checkpoints),
-            (&self.loras, &to.loras),
-            (&self.controlnet, &to.controlnet),
-            (&self.upscale_models, &to.upscale_models),
-            (&self.vae, &to.vae),
-            (&self.embeddings, &to.embeddings),
-        ];
+    /// Directory of a category, directly below the root if the structure does not configure it.
+    pub fn directory(&self, category: &str) -> PathBuf {
+        self.categories
+            .get(category)
+            .cloned()
+            .unwrap_or_else(|| self.root.join(category))
+    }
+
+    /// Every category directory of this structure.
+    pub fn directories(&self) -> impl Iterator<Item = &PathBuf> {
+        self.categories.values()
+    }
+
+    /// Pairs every category directory of this structure with the matching one in `to`, categories
+    /// only one of them has are left out.
+    pub fn pairs<'a>(&'a self, to: &'a Self) -> Vec<(&'a PathBuf, &'a PathBuf)> {
+        self.categories
+            .iter()
+            .filter_map(|(category, from)| Some((from, to.categories.get(category)?)))
+            .collect()
+    }
+
+    /// The pairs worth linking: categories present in this structure, or whose frontend
+    /// directory is about to be migrated into it. Linking the others would only leave dangling
+    /// links. Directories the plan creates count as present, so a dry run previews the same links
+    /// as a real run.
+    fn linked_pairs<'a>(&'a self, to: &'a Self, policy: ReplacePolicy, plan: &Plan) -> Vec<(&'a PathBuf, &'a PathBuf)> {
+        self.pairs(to)
+            .into_iter()
+            .filter(|(from, to_path)| {
+                let linked = plan.exists(from) || (policy == ReplacePolicy::Migrate && to_path.is_dir());
+                if !linked {
+                    debug!("Skipping {}, {} does not exist", to_path.display(), from.display());
+                }
+                linked
+            })
+            .collect()
+    }
+
+    /// Exposes this structure inside `to` using the given link mode.
+    pub fn link_to(&self, to: &Self, mode: LinkMode, policy: ReplacePolicy, store: &mut Store, plan: &mut Plan) -> Result<(), std::io::Error> {
+        match mode {
+            LinkMode::Symlink => self.soft_link_to(to, policy, store, plan),
+            _ => self.mirror_to(to, mode, policy, store, plan),
+        }
+    }
 
-        for (from, to_path) in paths {
-            debug!("Hard linking {} to {}", from.display(), to_path.display());
-            link::create_hard_link(from, to_path)?;
+    pub fn mirror_to(&self, to: &Self, mode: LinkMode, policy: ReplacePolicy, store: &mut Store, plan: &mut Plan) -> Result<(), std::io::Error> {
+        for (from, to_path) in self.linked_pairs(to, policy, plan) {
+            debug!("Mirroring {} to {} ({:?})", from.display(), to_path.display(), mode);
+            link::mirror_directory(from, to_path, mode, policy, store, plan)?;
         }
 
         Ok(())
     }
 
-    pub fn soft_link_to(&self, to: &Self) -> Result<(), std::io::Error> {
-        let paths = [
-            (&self.checkpoints, &to.checkpoints),
-            (&self.loras, &to.loras),
-            (&self.controlnet, &to.controlnet),
-            (&self.upscale_models, &to.upscale_models),
-            (&self.vae, &to.vae),
-            (&self.embeddings, &to.embeddings),
-        ];
+    pub fn unlink_from(&self, to: &Self, restore: bool, plan: &mut Plan) -> Result<(), std::io::Error> {
+        for (from, to_path) in self.pairs(to) {
+            debug!("Unlinking {} from {}", to_path.display(), from.display());
+            link::unlink_directory(from, to_path, restore, plan)?;
+        }
+
+        Ok(())
+    }
 
-        for (from, to_path) in paths {
+    pub fn soft_link_to(&self, to: &Self, policy: ReplacePolicy, store: &mut Store, plan: &mut Plan) -> Result<(), std::io::Error> {
+        for (from, to_path) in self.linked_pairs(to, policy, plan) {
             debug!("Soft linking {} to {}", from.display(), to_path.display());
-            link::create_symlink(from, to_path)?;
+            link::create_symlink(from, to_path, policy, store, plan)?;
         }
 
         Ok(())
@@ -79,29 +178,50 @@ impl FolderStructure {
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ComfyUIConfig {
+    #[serde(default = "get_default_enabled")]
+    pub enabled: bool,
     pub path: PathBuf,
-    #[serde(default = "get_default_structure_comfyui")]
+    #[serde(default)]
+    pub link_mode: LinkMode,
+    #[serde(default = "get_default_structure_comfyui", deserialize_with = "deserialize_structure_comfyui")]
     pub config: RelativeFolderStructure,
 }
 
 impl ComfyUIConfig {
     pub fn new<P: AsRef<Path>>(path: P) -> Self {
         Self {
+            enabled: true,
             path: path.as_ref().into(),
+            link_mode: LinkMode::default(),
             config: get_default_structure_comfyui(),
         }
     }
 }
 
+/// The folders of a ComfyUI `models` directory, including those of the common custom nodes.
 pub fn get_default_structure_comfyui() -> RelativeFolderStructure {
-    RelativeFolderStructure {
-        checkpoints: RelativePath::new("checkpoints").to_relative_path_buf(),
-        loras: RelativePath::new("loras").to_relative_path_buf(),
-        controlnet: RelativePath::new("controlnet").to_relative_path_buf(),
-        upscale_models: RelativePath::new("upscale_models").to_relative_path_buf(),
-        vae: RelativePath::new("vae").to_relative_path_buf(),
-        embeddings: RelativePath::new("embeddings").to_relative_path_buf(),
-    }
+    RelativeFolderStructure::from_pairs(&[
+        ("animatediff_models", "animatediff_models"),
+        ("checkpoints", "checkpoints"),
+        ("clip_vision", "clip_vision"),
+        ("controlnet", "controlnet"),
+        ("diffusion_models", "diffusion_models"),
+        ("embeddings", "embeddings"),
+        ("gligen", "gligen"),
+        ("hypernetworks", "hypernetworks"),
+        ("ipadapter", "ipadapter"),
+        ("loras", "loras"),
+        ("photomaker", "photomaker"),
+        ("style_models", "style_models"),
+        ("text_encoders", "text_encoders"),
+        ("upscale_models", "upscale_models"),
+        ("vae", "vae"),
+        ("vae_approx", "vae_approx"),
+    ])
+}
+
+fn deserialize_structure_comfyui<'de, D: Deserializer<'de>>(deserializer: D) -> Result<RelativeFolderStructure, D::Error> {
+    merge_structure(deserializer, get_default_structure_comfyui())
 }
 
 impl TryFrom<ComfyUIConfig> for FolderStructure {
@@ -117,29 +237,43 @@ impl TryFrom<ComfyUIConfig> for FolderStructure {
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct WebUIConfig {
+    #[serde(default = "get_default_enabled")]
+    pub enabled: bool,
     pub path: PathBuf,
-    #[serde(default = "get_default_structure_webui")]
+    #[serde(default)]
+    pub link_mode: LinkMode,
+    #[serde(default = "get_default_structure_webui", deserialize_with = "deserialize_structure_webui")]
     pub config: RelativeFolderStructure,
 }
 
 impl WebUIConfig {
     pub fn new<P: AsRef<Path>>(path: P) -> Self {
         Self {
+            enabled: true,
             path: path.as_ref().into(),
+            link_mode: LinkMode::default(),
             config: get_default_structure_webui(),
         }
     }
 }
 
+/// The model folders of a WebUI installation, `text_encoder` is only used by Forge.
 pub fn get_default_structure_webui() -> RelativeFolderStructure {
-    RelativeFolderStructure {
-        checkpoints: RelativePath::new("models/Stable-diffusion").to_relative_path_buf(),
-        loras: RelativePath::new("models/Lora").to_relative_path_buf(),
-        controlnet: RelativePath::new("models/ControlNet").to_relative_path_buf(),
-        upscale_models: RelativePath::new("models/ESRGAN").to_relative_path_buf(),
-        vae: RelativePath::new("models/VAE").to_relative_path_buf(),
-        embeddings: RelativePath::new("embeddings").to_relative_path_buf(),
-    }
+    RelativeFolderStructure::from_pairs(&[
+        ("checkpoints", "models/Stable-diffusion"),
+        ("controlnet", "models/ControlNet"),
+        ("embeddings", "embeddings"),
+        ("hypernetworks", "models/hypernetworks"),
+        ("loras", "models/Lora"),
+        ("text_encoders", "models/text_encoder"),
+        ("upscale_models", "models/ESRGAN"),
+        ("vae", "models/VAE"),
+        ("vae_approx", "models/VAE-approx"),
+    ])
+}
+
+fn deserialize_structure_webui<'de, D: Deserializer<'de>>(deserializer: D) -> Result<RelativeFolderStructure, D::Error> {
+    merge_structure(deserializer, get_default_structure_webui())
 }
 
 impl TryFrom<WebUIConfig> for FolderStructure {
@@ -153,28 +287,354 @@ impl TryFrom<WebUIConfig> for FolderStructure {
     }
 }
 
+pub fn get_default_enabled() -> bool {
+    true
+}
+
+/// Where `sort_models` gets the type and base model of an orphan from.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DetectionOrder {
+    /// Ask CivitAI, inspect the safetensors header if the model is not found there.
+    #[default]
+    CivitaiFirst,
+    /// Inspect the safetensors header, ask CivitAI if the header is inconclusive.
+    HeaderFirst,
+    /// Never ask CivitAI.
+    HeaderOnly,
+}
+
+impl std::str::FromStr for DetectionOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "civitai-first" => Ok(DetectionOrder::CivitaiFirst),
+            "header-first" => Ok(DetectionOrder::HeaderFirst),
+            "header-only" => Ok(DetectionOrder::HeaderOnly),
+            _ => Err(format!(
+                "Unknown detection order '{}', expected civitai-first, header-first or header-only",
+                s
+            )),
+        }
+    }
+}
+
+/// How `sort_models` names the models it moves.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RenameMode {
+    /// Keep the file name.
+    #[default]
+    Keep,
+    /// `<model name> <version name>`, as CivitAI shows them.
+    ModelVersion,
+    /// The name of the file on CivitAI.
+    CivitaiFile,
+}
+
 #[derive(Debug, Clone, Deserialize)]
+pub struct SortConfig {
+    #[serde(default)]
+    pub detection: DetectionOrder,
+    /// Write `.civitai.info` and `.json` metadata next to models found on CivitAI.
+    #[serde(default = "get_default_sidecars")]
+    pub sidecars: bool,
+    /// Download a preview image as `<model>.preview.png` and `<model>.png`.
+    #[serde(default)]
+    pub previews: bool,
+    /// Only use preview images up to this CivitAI `nsfwLevel`, 1 is safe for work. Any image is
+    /// used when unset.
+    pub max_nsfw_level: Option<u32>,
+    /// Category or folder of the general directory for models of types without a category of
+    /// their own, like CivitAI's "Other" or types added after this release.
+    #[serde(default = "get_default_fallback_directory")]
+    pub fallback_directory: String,
+    /// Name models found on CivitAI after it instead of keeping the downloaded file name.
+    #[serde(default)]
+    pub rename: RenameMode,
+    /// Where models are moved, relative to the general directory. See `template::PLACEHOLDERS`
+    /// for the values that can be used.
+    #[serde(default = "get_default_destination", deserialize_with = "deserialize_destination")]
+    pub destination: String,
+}
+
+impl Default for SortConfig {
+    fn default() -> Self {
+        Self {
+            detection: DetectionOrder::default(),
+            sidecars: get_default_sidecars(),
+            previews: false,
+            max_nsfw_level: None,
+            fallback_directory: get_default_fallback_directory(),
+            rename: RenameMode::default(),
+            destination: get_default_destination(),
+        }
+    }
+}
+
+pub fn get_default_sidecars() -> bool {
+    true
+}
+
+pub fn get_default_fallback_directory() -> String {
+    "other".to_string()
+}
+
+pub fn get_default_destination() -> String {
+    "{type}/{base_model:lower}/{file_name}".to_string()
+}
+
+/// Rejects unknown placeholders and templates without a file name when the config is read rather
+/// than for every model.
+fn deserialize_destination<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let destination = String::deserialize(deserializer)?;
+    template::check_destination(&destination).map_err(serde::de::Error::custom)?;
+    Ok(destination)
+}
+
+/// Where `sort` looks for new models besides the root of the general directory, and which files
+/// it takes.
+#[derive(Debug, Clone, Deserialize)]
+pub struct InboxConfig {
+    /// Directories searched recursively, like a browser's downloads folder.
+    #[serde(default)]
+    pub directories: Vec<PathBuf>,
+    /// Only files matching one of these patterns are taken, all files when empty.
+    #[serde(default, deserialize_with = "deserialize_patterns")]
+    pub include: Vec<Pattern>,
+    /// Files matching one of these patterns, or inside a folder that does, are left alone.
+    #[serde(default, deserialize_with = "deserialize_patterns")]
+    pub exclude: Vec<Pattern>,
+    /// Extensions of model files, without the dot.
+    #[serde(default = "get_default_extensions")]
+    pub extensions: Vec<String>,
+    /// Before sorting, recently modified files are measured twice this many milliseconds apart and
+    /// skipped while they grow, 0 disables the check.
+    #[serde(default = "get_default_growth_check_ms")]
+    pub growth_check_ms: u64,
+}
+
+impl Default for InboxConfig {
+    fn default() -> Self {
+        Self {
+            directories: vec![],
+            include: vec![],
+            exclude: vec![],
+            extensions: get_default_extensions(),
+            growth_check_ms: get_default_growth_check_ms(),
+        }
+    }
+}
+
+pub fn get_default_extensions() -> Vec<String> {
+    crate::api::MODEL_EXTENSIONS.map(String::from).to_vec()
+}
+
+pub fn get_default_growth_check_ms() -> u64 {
+    1000
+}
+
+fn deserialize_patterns<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Pattern>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|pattern| Pattern::new(pattern).map_err(|err| serde::de::Error::custom(format!("{}: {}", pattern, err))))
+        .collect()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CivitAiConfig {
+    /// Base URL of the CivitAI API, defaults to the public instance.
+    pub api_url: Option<String>,
+    /// Token for early access and restricted models.
+    pub api_key: Option<ApiKey>,
+    /// File containing the token, so it does not have to live in the config file.
+    pub api_key_file: Option<PathBuf>,
+    /// How often a request is repeated after rate limiting, server or connection errors.
+    #[serde(default = "get_default_max_retries")]
+    pub max_retries: u32,
+    /// Delay before the first retry in milliseconds, doubled for every further attempt unless
+    /// the server sends `Retry-After`.
+    #[serde(default = "get_default_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+    /// Upper bound for requests per second over the whole run, 0 disables the limit.
+    #[serde(default = "get_default_requests_per_second")]
+    pub requests_per_second: f64,
+    /// How long answers for models found on CivitAI are reused, in hours.
+    #[serde(default = "get_default_cache_ttl_hours")]
+    pub cache_ttl_hours: u64,
+    /// How long a model unknown to CivitAI is not asked about again, in hours.
+    #[serde(default = "get_default_not_found_ttl_hours")]
+    pub not_found_ttl_hours: u64,
+}
+
+impl Default for CivitAiConfig {
+    fn default() -> Self {
+        Self {
+            api_url: None,
+            api_key: None,
+            api_key_file: None,
+            max_retries: get_default_max_retries(),
+            retry_backoff_ms: get_default_retry_backoff_ms(),
+            requests_per_second: get_default_requests_per_second(),
+            cache_ttl_hours: get_default_cache_ttl_hours(),
+            not_found_ttl_hours: get_default_not_found_ttl_hours(),
+        }
+    }
+}
+
+pub fn get_default_max_retries() -> u32 {
+    3
+}
+
+pub fn get_default_retry_backoff_ms() -> u64 {
+    1000
+}
+
+pub fn get_default_requests_per_second() -> f64 {
+    2.0
+}
+
+pub fn get_default_cache_ttl_hours() -> u64 {
+    30 * 24
+}
+
+pub fn get_default_not_found_ttl_hours() -> u64 {
+    24
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HashConfig {
+    /// Files hashed at the same time, defaults to the number of CPUs up to 4.
+    pub jobs: Option<usize>,
+    /// Read buffer per file in KiB.
+    #[serde(default = "get_default_buffer_kb")]
+    pub buffer_kb: usize,
+}
+
+impl Default for HashConfig {
+    fn default() -> Self {
+        Self {
+            jobs: None,
+            buffer_kb: get_default_buffer_kb(),
+        }
+    }
+}
+
+pub fn get_default_buffer_kb() -> usize {
+    1024
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct Config {
-    pub comfyui: ComfyUIConfig,
-    pub webui: WebUIConfig,
+    pub general: Option<GeneralConfig>,
+    pub comfyui: Option<ComfyUIConfig>,
+    pub webui: Option<WebUIConfig>,
+    #[serde(default)]
+    pub sort: SortConfig,
+    #[serde(default)]
+    pub inbox: InboxConfig,
+    #[serde(default)]
+    pub civitai: CivitAiConfig,
+    #[serde(default)]
+    pub hash: HashConfig,
+}
+
+impl Config {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let config_data = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&config_data)?)
+    }
+
+    /// Resolves the general models directory, preferring the path given on the command line
+    /// while keeping any custom folder structure from the `[general]` section. Its path and those
+    /// of the inboxes are made canonical, and an inbox overlapping it is rejected, since sorting
+    /// would take the library for downloads or the downloads for the library.
+    pub fn resolve_general(&mut self, cli_path: Option<PathBuf>) -> Result<Option<GeneralConfig>, ConfigError> {
+        let general = match (cli_path, &self.general) {
+            (Some(path), Some(general)) => Some(GeneralConfig {
+                path,
+                config: general.config.clone(),
+            }),
+            (Some(path), None) => Some(GeneralConfig::new(path)),
+            (None, general) => general.clone(),
+        };
+        let Some(mut general) = general else {
+            return Ok(None);
+        };
+        general.path = general.path.canonicalize()?;
+
+        for directory in &mut self.inbox.directories {
+            // Missing inboxes are skipped with a warning when looking for orphans
+            if let Ok(canonical) = directory.canonicalize() {
+                *directory = canonical;
+            }
+            if directory.starts_with(&general.path) || general.path.starts_with(&*directory) {
+                return Err(ConfigError::InboxOverlap(format!(
+                    "{} and {}",
+                    directory.display(),
+                    general.path.display()
+                )));
+            }
+        }
+
+        Ok(Some(general))
+    }
+
+    /// Resolves the ComfyUI frontend. A path given on the command line always enables it,
+    /// otherwise the `[comfyui]` section is used if it is `enabled`.
+    pub fn resolve_comfyui(&self, cli_path: Option<PathBuf>) -> Option<ComfyUIConfig> {
+        match (cli_path, &self.comfyui) {
+            (Some(path), Some(comfyui)) => Some(ComfyUIConfig {
+                enabled: true,
+                path,
+                ..comfyui.clone()
+            }),
+            (Some(path), None) => Some(ComfyUIConfig::new(path)),
+            (None, comfyui) => comfyui.clone().filter(|c| c.enabled),
+        }
+    }
+
+    /// Resolves the WebUI frontend. A path given on the command line always enables it,
+    /// otherwise the `[webui]` section is used if it is `enabled`.
+    pub fn resolve_webui(&self, cli_path: Option<PathBuf>) -> Option<WebUIConfig> {
+        match (cli_path, &self.webui) {
+            (Some(path), Some(webui)) => Some(WebUIConfig {
+                enabled: true,
+                path,
+                ..webui.clone()
+            }),
+            (Some(path), None) => Some(WebUIConfig::new(path)),
+            (None, webui) => webui.clone().filter(|w| w.enabled),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct GeneralConfig {
     pub path: PathBuf,
-    #[serde(default = "get_default_structure_general")]
+    #[serde(default = "get_default_structure_general", deserialize_with = "deserialize_structure_general")]
     pub config: RelativeFolderStructure,
 }
 
+/// A folder for every category of both frontends and for every model type on CivitAI.
 pub fn get_default_structure_general() -> RelativeFolderStructure {
-    RelativeFolderStructure {
-        checkpoints: RelativePath::new("checkpoints").to_relative_path_buf(),
-        loras: RelativePath::new("loras").to_relative_path_buf(),
-        controlnet: RelativePath::new("controlnet").to_relative_path_buf(),
-        upscale_models: RelativePath::new("upscale_models").to_relative_path_buf(),
-        vae: RelativePath::new("vae").to_relative_path_buf(),
-        embeddings: RelativePath::new("embeddings").to_relative_path_buf(),
-    }
+    let mut structure = get_default_structure_comfyui();
+    structure.0.extend(
+        RelativeFolderStructure::from_pairs(&[
+            ("aesthetic_embeddings", "aesthetic_embeddings"),
+            ("other", "other"),
+            ("poses", "poses"),
+            ("wildcards", "wildcards"),
+            ("workflows", "workflows"),
+        ])
+        .0,
+    );
+    structure
+}
+
+fn deserialize_structure_general<'de, D: Deserializer<'de>>(deserializer: D) -> Result<RelativeFolderStructure, D::Error> {
+    merge_structure(deserializer, get_default_structure_general())
 }
 
 impl GeneralConfig {
@@ -1,13 +1,28 @@
-use std::io::BufReader;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::time::UNIX_EPOCH;
 
 use data_encoding::HEXUPPER;
+use indicatif::MultiProgress;
+use indicatif::ProgressBar;
+use indicatif::ProgressStyle;
 use ring::digest::SHA256;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::interrupt;
+
+/// Read buffer used when none is configured, large enough to keep fast drives busy.
+pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
 
 #[derive(Debug)]
 pub enum EldenError {
     Io(String),
     Hash(String),
+    Interrupted,
 }
 
 impl std::fmt::Display for EldenError {
@@ -15,6 +30,7 @@ impl std::fmt::Display for EldenError {
         match self {
             EldenError::Io(s) => write!(f, "{}", s),
             EldenError::Hash(s) => write!(f, "{}", s),
+            EldenError::Interrupted => write!(f, "Interrupted"),
         }
     }
 }
@@ -35,11 +51,159 @@ impl std::error::Error for EldenError {}
 
 type Result<T> = std::result::Result<T, EldenError>;
 
+/// Region of the file the legacy A1111 hash covers.
+const AUTO_V1_OFFSET: u64 = 0x100000;
+const AUTO_V1_LENGTH: u64 = 0x10000;
+
+/// Safetensors headers above this size are not believed, so AutoV3 is skipped for such files.
+const MAX_HEADER_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Every hash CivitAI lists for a file, upper case hex like on the site. Only the SHA256 is known
+/// for hashes taken over from elsewhere, the others need a pass over the file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelHashes {
+    pub sha256: String,
+    /// SHA256 of the 64 KiB at offset 1 MiB, shortened to 8 characters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_v1: Option<String>,
+    /// SHA256 of the tensor data after the safetensors header, shortened to 12 characters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_v3: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crc32: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blake3: Option<String>,
+}
+
+impl ModelHashes {
+    pub fn from_sha256(sha256: String) -> Self {
+        Self {
+            sha256,
+            auto_v1: None,
+            auto_v3: None,
+            crc32: None,
+            blake3: None,
+        }
+    }
+
+    /// Whether these came from a full pass over the file rather than only carrying the SHA256.
+    pub fn is_complete(&self) -> bool {
+        self.auto_v1.is_some() && self.crc32.is_some() && self.blake3.is_some()
+    }
+
+    /// The SHA256 shortened to 10 characters, which is what the WebUI shows as model hash.
+    pub fn auto_v2(&self) -> &str {
+        &self.sha256[..10]
+    }
+
+    /// Names and values of the known hashes in the order CivitAI lists them.
+    pub fn all(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("AutoV1", self.auto_v1.as_deref()),
+            ("AutoV2", Some(self.auto_v2())),
+            ("SHA256", Some(self.sha256.as_str())),
+            ("CRC32", self.crc32.as_deref()),
+            ("BLAKE3", self.blake3.as_deref()),
+            ("AutoV3", self.auto_v3.as_deref()),
+        ]
+        .into_iter()
+        .filter_map(|(name, hash)| Some((name, hash?)))
+        .collect()
+    }
+}
+
+/// Feeds one stream of bytes into all hashes at once, keeping track of the regions only some of
+/// them cover.
+struct MultiHasher {
+    offset: u64,
+    sha256: ring::digest::Context,
+    auto_v1: ring::digest::Context,
+    /// Start of the tensor data once the header size is known, `None` for other formats.
+    auto_v3: Option<(ring::digest::Context, Option<u64>)>,
+    header_size: Vec<u8>,
+    crc32: crc32fast::Hasher,
+    blake3: blake3::Hasher,
+}
+
+impl MultiHasher {
+    fn new(safetensors: bool) -> Self {
+        Self {
+            offset: 0,
+            sha256: ring::digest::Context::new(&SHA256),
+            auto_v1: ring::digest::Context::new(&SHA256),
+            auto_v3: safetensors.then(|| (ring::digest::Context::new(&SHA256), None)),
+            header_size: Vec::with_capacity(8),
+            crc32: crc32fast::Hasher::new(),
+            blake3: blake3::Hasher::new(),
+        }
+    }
+
+    fn update(&mut self, chunk: &[u8]) {
+        let start = self.offset;
+        let end = start + chunk.len() as u64;
+        self.offset = end;
+
+        self.sha256.update(chunk);
+        self.crc32.update(chunk);
+        self.blake3.update(chunk);
+        if let Some(range) = overlap(start, end, AUTO_V1_OFFSET, AUTO_V1_OFFSET + AUTO_V1_LENGTH) {
+            self.auto_v1.update(&chunk[range]);
+        }
+
+        if self.header_size.len() < 8 {
+            let missing = (8 - self.header_size.len()).min(chunk.len());
+            self.header_size.extend_from_slice(&chunk[..missing]);
+        }
+        let Some((context, data_start)) = &mut self.auto_v3 else {
+            return;
+        };
+        if data_start.is_none() && self.header_size.len() == 8 {
+            let size = u64::from_le_bytes(self.header_size.as_slice().try_into().unwrap_or_default());
+            if size > MAX_HEADER_SIZE {
+                self.auto_v3 = None;
+                return;
+            }
+            *data_start = Some(8 + size);
+        }
+        if let Some(range) = data_start.and_then(|data_start| overlap(start, end, data_start, u64::MAX)) {
+            context.update(&chunk[range]);
+        }
+    }
+
+    fn finish(self) -> ModelHashes {
+        let auto_v3 = match self.auto_v3 {
+            Some((context, Some(data_start))) if self.offset >= data_start => Some(hex(context.finish().as_ref(), 12)),
+            _ => None,
+        };
+        ModelHashes {
+            sha256: HEXUPPER.encode(self.sha256.finish().as_ref()),
+            auto_v1: Some(hex(self.auto_v1.finish().as_ref(), 8)),
+            auto_v3,
+            crc32: Some(format!("{:08X}", self.crc32.finalize())),
+            blake3: Some(HEXUPPER.encode(self.blake3.finalize().as_bytes())),
+        }
+    }
+}
+
+/// Part of the chunk covering `start..end` of the file that falls into `from..to`.
+fn overlap(start: u64, end: u64, from: u64, to: u64) -> Option<std::ops::Range<usize>> {
+    let first = start.max(from);
+    let last = end.min(to);
+    (first < last).then(|| (first - start) as usize..(last - start) as usize)
+}
+
+fn hex(digest: &[u8], length: usize) -> String {
+    let mut hex = HEXUPPER.encode(digest);
+    hex.truncate(length);
+    hex
+}
+
 pub struct EldenRing;
 impl EldenRing {
+    /// Only the SHA256, cheaper than `calculate_hashes_with` when comparing files.
     pub fn calculate_hash_sha256<R: std::io::Read>(mut reader: R) -> Result<String> {
         let mut context = ring::digest::Context::new(&SHA256);
-        let mut buffer = [0; 1024 * 64];
+        let mut buffer = vec![0; DEFAULT_BUFFER_SIZE];
 
         loop {
             let count = reader.read(&mut buffer)?;
@@ -49,14 +213,166 @@ impl EldenRing {
             context.update(&buffer[..count]);
         }
 
-        let digest = context.finish();
-        let hash = HEXUPPER.encode(digest.as_ref());
+        Ok(HEXUPPER.encode(context.finish().as_ref()))
+    }
+
+    /// Calculates every hash in a single pass over `reader`, reading chunks of `buffer_size` and
+    /// reporting the bytes read after each. AutoV3 needs the layout of a safetensors file.
+    pub fn calculate_hashes_with<R: std::io::Read, F: FnMut(u64)>(mut reader: R, safetensors: bool, buffer_size: usize, mut progress: F) -> Result<ModelHashes> {
+        let mut hasher = MultiHasher::new(safetensors);
+        let mut buffer = vec![0; buffer_size.max(4096)];
+
+        loop {
+            interrupt::check().map_err(|_| EldenError::Interrupted)?;
+            let count = reader.read(&mut buffer)?;
+            if count == 0 {
+                break;
+            }
+            hasher.update(&buffer[..count]);
+            progress(count as u64);
+        }
 
-        Ok(hash)
+        Ok(hasher.finish())
     }
 
     pub fn from_file<P: AsRef<Path>>(filepath: P) -> Result<String> {
-        let reader = BufReader::new(std::fs::File::open(filepath)?);
-        Self::calculate_hash_sha256(reader)
+        Self::calculate_hash_sha256(std::fs::File::open(filepath)?)
+    }
+
+    pub fn hashes_from_file<P: AsRef<Path>>(filepath: P) -> Result<ModelHashes> {
+        let file = std::fs::File::open(&filepath)?;
+        Self::calculate_hashes_with(file, is_safetensors(filepath.as_ref()), DEFAULT_BUFFER_SIZE, |_| ())
+    }
+}
+
+fn is_safetensors(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "safetensors")
+}
+
+/// How many files are hashed at once and with how large a read buffer each.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HashOptions {
+    pub jobs: usize,
+    pub buffer_size: usize,
+}
+
+/// One job per CPU, but no more than 4 since beyond that the drive is the bottleneck.
+pub fn default_jobs() -> usize {
+    std::thread::available_parallelism().map_or(1, |jobs| jobs.get()).min(4)
+}
+
+/// Hashes `files` on a pool of `options.jobs` threads while showing a bar per file and one for
+/// the total with throughput and ETA. `on_hashed` is called on the calling thread as soon as a
+/// file is done.
+pub fn hash_files<F: FnMut(&Path, Result<ModelHashes>)>(files: &[PathBuf], options: HashOptions, mut on_hashed: F) {
+    let sizes: Vec<u64> = files
+        .iter()
+        .map(|file| std::fs::metadata(file).map_or(0, |metadata| metadata.len()))
+        .collect();
+
+    let progress = MultiProgress::new();
+    let total = progress.add(ProgressBar::new(sizes.iter().sum()));
+    total.set_style(progress_style("{spinner} {msg:<20} [{bar:30}] {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta}"));
+    total.set_message(format!("Hashing {} files", files.len()));
+
+    let next_file = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    std::thread::scope(|scope| {
+        for _ in 0..options.jobs.clamp(1, files.len().max(1)) {
+            let sender = sender.clone();
+            let (next_file, progress, total, sizes) = (&next_file, &progress, &total, &sizes);
+            scope.spawn(move || {
+                while !interrupt::is_interrupted() {
+                    let index = next_file.fetch_add(1, Ordering::Relaxed);
+                    let Some(file) = files.get(index) else {
+                        break;
+                    };
+
+                    let bar = progress.insert_before(total, ProgressBar::new(sizes[index]));
+                    bar.set_style(progress_style("  {msg:<20!} [{bar:30}] {bytes}/{total_bytes} {binary_bytes_per_sec}"));
+                    bar.set_message(file.file_name().unwrap_or_default().to_string_lossy().to_string());
+
+                    let result = std::fs::File::open(file)
+                        .map_err(EldenError::from)
+                        .and_then(|reader| {
+                            EldenRing::calculate_hashes_with(reader, is_safetensors(file), options.buffer_size, |count| {
+                                bar.inc(count);
+                                total.inc(count);
+                            })
+                        });
+                    bar.finish_and_clear();
+
+                    if sender.send((index, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        for (index, result) in receiver {
+            on_hashed(&files[index], result);
+        }
+    });
+
+    total.finish_and_clear();
+}
+
+fn progress_style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .unwrap_or_else(|_| ProgressStyle::default_bar())
+        .progress_chars("=> ")
+}
+
+/// Properties of a file that change when its contents are replaced, so a cached hash is only
+/// trusted while they match. Inode and device also find the file again after a rename.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub size: u64,
+    /// Modification time in nanoseconds since the Unix epoch.
+    pub mtime: u64,
+    pub inode: Option<u64>,
+    pub device: Option<u64>,
+}
+
+impl Fingerprint {
+    pub fn from_file<P: AsRef<Path>>(filepath: P) -> Result<Self> {
+        Ok(Self::from_metadata(&std::fs::metadata(filepath)?))
+    }
+
+    pub fn from_metadata(metadata: &std::fs::Metadata) -> Self {
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_nanos() as u64)
+            .unwrap_or_default();
+
+        #[cfg(unix)]
+        let (inode, device) = {
+            use std::os::unix::fs::MetadataExt;
+            (Some(metadata.ino()), Some(metadata.dev()))
+        };
+        #[cfg(not(unix))]
+        let (inode, device) = (None, None);
+
+        Self {
+            size: metadata.len(),
+            mtime,
+            inode,
+            device,
+        }
+    }
+
+    /// Whether both describe the same unchanged file, possibly under different names. Without an
+    /// inode only size and modification time can be compared, which is not enough to follow a
+    /// rename.
+    pub fn is_same_file(&self, other: &Fingerprint) -> bool {
+        self.inode.is_some() && self == other
+    }
+
+    /// Device and inode, which identify the file under any name.
+    pub fn file_id(&self) -> Option<(u64, u64)> {
+        Some((self.device?, self.inode?))
     }
 }
@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use glob::MatchOptions;
+use glob::Pattern;
+use log::debug;
+use log::info;
+use log::warn;
+
+use crate::configuration::InboxConfig;
+use crate::link;
+
+/// Suffixes browsers and download managers use while a download is running. Some also create the
+/// file under its final name right away, so a model with such a file next to it is skipped too.
+const PARTIAL_SUFFIXES: [&str; 4] = [".part", ".crdownload", ".partial", ".aria2"];
+
+/// Files modified this recently may still be downloading and are checked for growth.
+const RECENTLY_MODIFIED: Duration = Duration::from_secs(60);
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Model files waiting to be sorted: those directly in `root` and those anywhere below the inbox
+/// directories, leaving out downloads that are marked as unfinished. Telling whether a file is
+/// still being written takes a while, so `skip_growing` is left to the steps that move files.
+pub fn find_orphans(root: &Path, config: &InboxConfig) -> std::io::Result<Vec<PathBuf>> {
+    let mut candidates = vec![];
+    for entry in std::fs::read_dir(root)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            candidates.push((root.to_path_buf(), path));
+        }
+    }
+    for directory in &config.directories {
+        match link::collect_files(directory) {
+            Ok(files) => candidates.extend(files.into_iter().map(|file| (directory.clone(), file))),
+            Err(err) => warn!("Skipping inbox {}: {}", directory.display(), err),
+        }
+    }
+
+    Ok(candidates
+        .into_iter()
+        .filter(|(inbox, file)| is_wanted(inbox, file, config))
+        .map(|(_, file)| file)
+        .collect())
+}
+
+fn is_wanted(inbox: &Path, file: &Path, config: &InboxConfig) -> bool {
+    let extension = file.extension().unwrap_or_default().to_string_lossy();
+    let is_model = config
+        .extensions
+        .iter()
+        .any(|allowed| allowed.trim_start_matches('.').eq_ignore_ascii_case(&extension));
+    if !is_model {
+        return false;
+    }
+    if is_partial_download(file) {
+        info!("Skipping {}, it is still downloading", file.display());
+        return false;
+    }
+
+    let relative = file.strip_prefix(inbox).unwrap_or(file);
+    if let Some(pattern) = config.exclude.iter().find(|pattern| is_excluded(pattern, relative)) {
+        debug!("Skipping {}, it matches {}", file.display(), pattern);
+        return false;
+    }
+    config.include.is_empty() || config.include.iter().any(|pattern| matches(pattern, relative))
+}
+
+fn is_partial_download(file: &Path) -> bool {
+    PARTIAL_SUFFIXES.iter().any(|suffix| {
+        let mut partial = OsString::from(file.as_os_str());
+        partial.push(suffix);
+        Path::new(&partial).exists()
+    })
+}
+
+/// Patterns without `/` apply to the file name, others to the path relative to the inbox.
+fn matches(pattern: &Pattern, relative: &Path) -> bool {
+    if pattern.as_str().contains('/') {
+        return pattern.matches_path_with(relative, MATCH_OPTIONS);
+    }
+    relative
+        .file_name()
+        .is_some_and(|name| pattern.matches_with(&name.to_string_lossy(), MATCH_OPTIONS))
+}
+
+/// Like `matches`, but patterns without `/` also apply to the folders the file is in.
+fn is_excluded(pattern: &Pattern, relative: &Path) -> bool {
+    if pattern.as_str().contains('/') {
+        return pattern.matches_path_with(relative, MATCH_OPTIONS);
+    }
+    relative
+        .iter()
+        .any(|component| pattern.matches_with(&component.to_string_lossy(), MATCH_OPTIONS))
+}
+
+/// Leaves out files whose size or modification time changes within `check_ms`. Only recently
+/// modified files are watched, so there is no wait when every file is settled.
+pub fn skip_growing(files: Vec<PathBuf>, check_ms: u64) -> Vec<PathBuf> {
+    if check_ms == 0 {
+        return files;
+    }
+
+    let recent: BTreeMap<&Path, (u64, SystemTime)> = files
+        .iter()
+        .filter_map(|file| {
+            let state = file_state(file)?;
+            let age = state.1.elapsed().unwrap_or_default();
+            (age < RECENTLY_MODIFIED).then_some((file.as_path(), state))
+        })
+        .collect();
+    if recent.is_empty() {
+        return files;
+    }
+
+    debug!("Checking whether {} recently modified files are still growing", recent.len());
+    std::thread::sleep(Duration::from_millis(check_ms));
+    let growing: Vec<PathBuf> = recent
+        .into_iter()
+        .filter(|(file, state)| file_state(file).as_ref() != Some(state))
+        .map(|(file, _)| file.to_path_buf())
+        .collect();
+
+    files
+        .into_iter()
+        .filter(|file| {
+            let is_growing = growing.contains(file);
+            if is_growing {
+                info!("Skipping {}, it is still being written", file.display());
+            }
+            !is_growing
+        })
+        .collect()
+}
+
+fn file_state(file: &Path) -> Option<(u64, SystemTime)> {
+    let metadata = std::fs::metadata(file).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
@@ -0,0 +1,39 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Returned by long running steps once Ctrl-C was pressed.
+#[derive(Debug, PartialEq)]
+pub struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Makes the first Ctrl-C stop the run instead of killing it. Files still being hashed are
+/// abandoned, the hashes that were finished are saved to the store. A second Ctrl-C exits right
+/// away.
+pub fn install_handler() -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        eprintln!("Interrupted, saving progress. Press Ctrl-C again to quit right away");
+    })
+}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+pub fn check() -> Result<(), Interrupted> {
+    if is_interrupted() {
+        return Err(Interrupted);
+    }
+    Ok(())
+}
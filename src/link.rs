@@ -1,10 +1,27 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+
 use log::debug;
+use log::info;
+use log::warn;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::hash::EldenRing;
+use crate::hash::Fingerprint;
+use crate::hash::ModelHashes;
+use crate::plan::Plan;
+use crate::store::Store;
 
 #[derive(Debug)]
 pub enum LinkError {
     Io(String),
     InvalidPath(String),
-    Unspecified(String),
+    TargetNotEmpty(String),
+    Hash(String),
 }
 
 impl std::fmt::Display for LinkError {
@@ -12,7 +29,13 @@ impl std::fmt::Display for LinkError {
         match self {
             LinkError::Io(msg) => f.write_str(msg),
             LinkError::InvalidPath(msg) => f.write_str(msg),
-            LinkError::Unspecified(msg) => f.write_str(msg),
+            LinkError::TargetNotEmpty(path) => write!(
+                f,
+                "Refusing to replace {} because it contains files, use --migrate to move them \
+                into the general directory or --force to delete them",
+                path
+            ),
+            LinkError::Hash(msg) => f.write_str(msg),
         }
     }
 }
@@ -23,9 +46,21 @@ impl From<std::io::Error> for LinkError {
     }
 }
 
+impl From<crate::hash::EldenError> for LinkError {
+    fn from(e: crate::hash::EldenError) -> Self {
+        LinkError::Hash(e.to_string())
+    }
+}
+
+impl From<crate::store::StoreError> for LinkError {
+    fn from(e: crate::store::StoreError) -> Self {
+        LinkError::Io(e.to_string())
+    }
+}
+
 impl From<LinkError> for std::io::Error {
     fn from(e: LinkError) -> Self {
-        std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+        std::io::Error::other(e.to_string())
     }
 }
 
@@ -33,28 +68,309 @@ impl std::error::Error for LinkError {}
 
 type Result<T> = std::result::Result<T, LinkError>;
 
-pub fn create_hard_link(source: &std::path::Path, target: &std::path::Path) -> Result<()> {
+/// What to do with a real file or directory that sits where a link has to be created.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ReplacePolicy {
+    /// Only replace links and empty directories, fail on anything else.
+    #[default]
+    Abort,
+    /// Move files into the general directory first, dropping ones that are already there.
+    Migrate,
+    /// Delete whatever is in the way.
+    Force,
+}
+
+/// How the general directory is exposed inside a frontend.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkMode {
+    /// Replace each frontend category directory with a symlink to the general one.
+    #[default]
+    Symlink,
+    /// Mirror the general tree with per-file symlinks, keeping the frontend's own files.
+    Mirror,
+    /// Mirror the general tree with per-file hard links.
+    Hardlink,
+    /// Mirror the general tree with per-file copy-on-write clones, copying where unsupported.
+    Reflink,
+    /// Mirror the general tree with plain copies.
+    Copy,
+}
+
+impl std::str::FromStr for LinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "symlink" => Ok(LinkMode::Symlink),
+            "mirror" => Ok(LinkMode::Mirror),
+            "hardlink" => Ok(LinkMode::Hardlink),
+            "reflink" => Ok(LinkMode::Reflink),
+            "copy" => Ok(LinkMode::Copy),
+            _ => Err(format!(
+                "Unknown link mode '{}', expected symlink, mirror, hardlink, reflink or copy",
+                s
+            )),
+        }
+    }
+}
+
+/// Name of the file in each mirrored directory that lists the entries created by us.
+pub const MANIFEST_FILE_NAME: &str = ".model_sync_managed.json";
+
+/// The entries of a mirrored directory created by us, by relative path. Files that are not
+/// symlinks carry their fingerprint, so a file the user put in their place is told apart.
+pub type Manifest = BTreeMap<String, Option<Fingerprint>>;
+
+/// Manifests of earlier releases only listed the paths.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredManifest {
+    Current(Manifest),
+    Legacy(BTreeSet<String>),
+}
+
+/// Recreates the file tree of `source` inside the real directory `target`, one file at a time.
+/// Files the frontend keeps on its own are left alone, entries created by a previous run that no
+/// longer exist in `source` are pruned.
+pub fn mirror_directory(source: &Path, target: &Path, mode: LinkMode, policy: ReplacePolicy, store: &mut Store, plan: &mut Plan) -> Result<()> {
+    if target.symlink_metadata().is_ok_and(|m| m.is_symlink()) {
+        remove_existing_path(source, target, policy, store, plan)?;
+    }
+    if !target.is_dir() {
+        if target.symlink_metadata().is_ok() {
+            remove_existing_path(source, target, policy, store, plan)?;
+        }
+        plan.create_dir_all(target)?;
+    }
+
+    let previously_managed = read_manifest(target)?;
+    let mut managed = Manifest::new();
+
     if source.is_dir() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Cannot hard link directories, use soft_link_to instead",
-        )
-        .into());
+        for file in collect_files(source)? {
+            let Ok(relative) = file.strip_prefix(source) else {
+                return Err(LinkError::InvalidPath(file.display().to_string()));
+            };
+            let relative = relative.to_string_lossy().replace('\\', "/");
+
+            // Entries from a previous run are ours to replace, anything else belongs to the user
+            let target_path = target.join(&relative);
+            let previous = previously_managed.get(&relative);
+            let file_policy = if previous.is_some_and(|fingerprint| is_still_managed(&target_path, source, fingerprint.as_ref())) {
+                ReplacePolicy::Force
+            } else {
+                policy
+            };
+            link_file(&file, &target_path, mode, file_policy, store, plan)?;
+
+            // Nothing was written during a dry run, so the entry keeps its last fingerprint
+            let fingerprint = match target_path.symlink_metadata() {
+                Ok(metadata) if !metadata.is_symlink() => Some(Fingerprint::from_metadata(&metadata)),
+                _ => previous.cloned().flatten(),
+            };
+            managed.insert(relative, fingerprint);
+        }
+    }
+
+    for (stale, fingerprint) in &previously_managed {
+        if managed.contains_key(stale) {
+            continue;
+        }
+        let stale_path = target.join(stale);
+        if stale_path.symlink_metadata().is_err() {
+            continue;
+        }
+        if !is_still_managed(&stale_path, source, fingerprint.as_ref()) {
+            warn!("Not pruning {}, it was replaced since it was linked", stale_path.display());
+            continue;
+        }
+        debug!("Pruning stale entry {}", stale_path.display());
+        plan.remove_file(&stale_path)?;
+        remove_empty_parents(&stale_path, target, plan)?;
+    }
+
+    if managed != previously_managed {
+        write_manifest(target, &managed, plan)?;
     }
 
-    if target.exists() {
-        remove_existing_path(target)?;
+    Ok(())
+}
+
+/// Returns the entries recorded in the manifest of a mirrored directory.
+pub fn read_manifest(target: &Path) -> Result<Manifest> {
+    let manifest_path = target.join(MANIFEST_FILE_NAME);
+    if !manifest_path.is_file() {
+        return Ok(Manifest::new());
+    }
+
+    let manifest_data = std::fs::read_to_string(&manifest_path)?;
+    let manifest = serde_json::from_str(&manifest_data)
+        .map_err(|e| LinkError::Io(format!("Invalid manifest {}: {}", manifest_path.display(), e)))?;
+    match manifest {
+        StoredManifest::Current(manifest) => Ok(manifest),
+        StoredManifest::Legacy(paths) => Ok(paths.into_iter().map(|path| (path, None)).collect()),
+    }
+}
+
+/// Whether the entry at `path` is still the one created from `source`: a link into `source`, or a
+/// file unchanged since it was linked or copied. Files listed without a fingerprint are never
+/// assumed to be ours.
+fn is_still_managed(path: &Path, source: &Path, fingerprint: Option<&Fingerprint>) -> bool {
+    let Ok(metadata) = path.symlink_metadata() else {
+        return false;
+    };
+    if metadata.is_symlink() {
+        return link_destination(path).is_ok_and(|destination| is_inside(&destination, source));
+    }
+    fingerprint.is_some_and(|fingerprint| *fingerprint == Fingerprint::from_metadata(&metadata))
+}
+
+fn write_manifest(target: &Path, managed: &Manifest, plan: &mut Plan) -> Result<()> {
+    let manifest_path = target.join(MANIFEST_FILE_NAME);
+    let manifest_data = serde_json::to_string_pretty(managed)
+        .map_err(|e| LinkError::Io(e.to_string()))?;
+    plan.write_file(&manifest_path, manifest_data)?;
+    Ok(())
+}
+
+/// Removes directories between `path` and `root` that became empty, `root` itself is kept.
+fn remove_empty_parents(path: &Path, root: &Path, plan: &mut Plan) -> Result<()> {
+    let mut current = path.parent();
+    while let Some(dir) = current {
+        if dir == root || !dir.starts_with(root) {
+            break;
+        }
+        let remaining = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| !plan.is_removed(&entry.path()))
+            .count();
+        if remaining > 0 {
+            break;
+        }
+        plan.remove_dir_all(dir)?;
+        current = dir.parent();
+    }
+    Ok(())
+}
+
+/// How a frontend directory currently relates to the general directory.
+#[derive(Debug, PartialEq)]
+pub enum LinkState {
+    Missing,
+    Symlink { points_to: PathBuf, into_general: bool },
+    Mirrored { managed: usize, own: usize },
+    Directory { files: usize },
+    File,
+}
+
+impl std::fmt::Display for LinkState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkState::Missing => write!(f, "missing"),
+            LinkState::Symlink { points_to, into_general: true } => write!(f, "linked -> {}", points_to.display()),
+            LinkState::Symlink { points_to, into_general: false } => {
+                write!(f, "foreign link -> {}", points_to.display())
+            }
+            LinkState::Mirrored { managed, own } => write!(f, "mirrored ({} managed, {} own files)", managed, own),
+            LinkState::Directory { files } => write!(f, "not linked ({} files)", files),
+            LinkState::File => write!(f, "not linked (file in the way)"),
+        }
+    }
+}
+
+pub fn link_state(source: &Path, target: &Path) -> Result<LinkState> {
+    let Ok(metadata) = target.symlink_metadata() else {
+        return Ok(LinkState::Missing);
+    };
+
+    if metadata.is_symlink() {
+        let points_to = std::fs::read_link(target)?;
+        let into_general = is_inside(&link_destination(target)?, source);
+        return Ok(LinkState::Symlink { points_to, into_general });
+    }
+
+    if !metadata.is_dir() {
+        return Ok(LinkState::File);
+    }
+
+    let managed = read_manifest(target)?;
+    let files = collect_files(target)?
+        .into_iter()
+        .filter(|file| file.file_name().is_none_or(|name| name != MANIFEST_FILE_NAME))
+        .count();
+
+    if managed.is_empty() {
+        Ok(LinkState::Directory { files })
     } else {
-        ensure_parent_directory(target)?;
+        Ok(LinkState::Mirrored {
+            managed: managed.len(),
+            own: files.saturating_sub(managed.len()),
+        })
+    }
+}
+
+/// Links or copies a single file, skipping it if `target` is already up to date.
+pub fn link_file(source: &Path, target: &Path, mode: LinkMode, policy: ReplacePolicy, store: &mut Store, plan: &mut Plan) -> Result<()> {
+    if source.is_dir() {
+        return Err(LinkError::InvalidPath(format!(
+            "Cannot link directory {} as a file",
+            source.display()
+        )));
+    }
+
+    if target.symlink_metadata().is_ok() {
+        if is_up_to_date(source, target, mode)? {
+            debug!("{} is up to date", target.display());
+            return Ok(());
+        }
+        remove_existing_path(source, target, policy, store, plan)?;
     }
+    ensure_parent_directory(target, plan)?;
 
-    std::fs::hard_link(source, target)?;
-    debug!("Created hard link successfully");
+    match mode {
+        LinkMode::Symlink | LinkMode::Mirror => plan.symlink(source, target)?,
+        LinkMode::Hardlink => plan.hard_link(source, target)?,
+        LinkMode::Reflink => plan.reflink(source, target)?,
+        LinkMode::Copy => plan.copy(source, target)?,
+    }
 
     Ok(())
 }
 
-pub fn create_symlink(source: &std::path::Path, target: &std::path::Path) -> Result<()> {
+fn is_up_to_date(source: &Path, target: &Path, mode: LinkMode) -> Result<bool> {
+    match mode {
+        LinkMode::Symlink | LinkMode::Mirror => Ok(std::fs::read_link(target).is_ok_and(|link| link == source)),
+        LinkMode::Hardlink => is_same_file(source, target),
+        LinkMode::Reflink | LinkMode::Copy => {
+            let target_metadata = target.symlink_metadata()?;
+            let source_metadata = source.metadata()?;
+            Ok(target_metadata.is_file()
+                && target_metadata.len() == source_metadata.len()
+                && target_metadata.modified()? >= source_metadata.modified()?)
+        }
+    }
+}
+
+fn is_same_file(a: &Path, b: &Path) -> Result<bool> {
+    let a_metadata = a.metadata()?;
+    let b_metadata = b.symlink_metadata()?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Ok(a_metadata.dev() == b_metadata.dev() && a_metadata.ino() == b_metadata.ino())
+    }
+
+    #[cfg(not(unix))]
+    {
+        Ok(b_metadata.is_file()
+            && a_metadata.len() == b_metadata.len()
+            && a_metadata.modified()? == b_metadata.modified()?)
+    }
+}
+
+pub fn create_symlink(source: &std::path::Path, target: &std::path::Path, policy: ReplacePolicy, store: &mut Store, plan: &mut Plan) -> Result<()> {
     if should_skip_existing_link(source, target)? {
         debug!(
             "Link already exists and points to correct target: {}",
@@ -63,18 +379,18 @@ pub fn create_symlink(source: &std::path::Path, target: &std::path::Path) -> Res
         return Ok(());
     }
 
-    if target.exists() {
-        remove_existing_path(target)?;
+    if target.symlink_metadata().is_ok() {
+        remove_existing_path(source, target, policy, store, plan)?;
     }
-    ensure_parent_directory(target)?;
-    create_platform_specific_symlink(source, target)?;
+    ensure_parent_directory(target, plan)?;
+    plan.symlink(source, target)?;
 
     debug!("Created symlink successfully");
     Ok(())
 }
 
 fn should_skip_existing_link(source: &std::path::Path, target: &std::path::Path) -> Result<bool> {
-    if !target.exists() {
+    if target.symlink_metadata().is_err() {
         return Ok(false);
     }
 
@@ -93,29 +409,284 @@ fn should_skip_existing_link(source: &std::path::Path, target: &std::path::Path)
     Ok(false)
 }
 
-fn remove_existing_path(path: &std::path::Path) -> Result<()> {
+fn remove_existing_path(source: &Path, path: &Path, policy: ReplacePolicy, store: &mut Store, plan: &mut Plan) -> Result<()> {
     debug!("Removing existing path: {}", path.display());
 
+    let metadata = path.symlink_metadata()?;
+    if metadata.is_symlink() {
+        remove_symlink(path, plan)?;
+        return Ok(());
+    }
+
+    if metadata.is_dir() && collect_files(path)?.iter().all(|file| is_placeholder(file)) {
+        plan.remove_dir_all(path)?;
+        return Ok(());
+    }
+
+    match policy {
+        ReplacePolicy::Abort => Err(LinkError::TargetNotEmpty(path.display().to_string())),
+        ReplacePolicy::Migrate if metadata.is_dir() => {
+            migrate_directory(path, source, store, plan)?;
+            plan.remove_dir_all(path)?;
+            Ok(())
+        }
+        ReplacePolicy::Migrate => {
+            migrate_file(path, source, store, plan)?;
+            Ok(())
+        }
+        ReplacePolicy::Force => {
+            warn!("Deleting {} to make room for a link", path.display());
+            if metadata.is_dir() {
+                plan.remove_dir_all(path)?;
+            } else {
+                plan.remove_file(path)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn remove_symlink(path: &Path, plan: &mut Plan) -> Result<()> {
+    #[cfg(windows)]
     if path.is_dir() {
-        std::fs::remove_dir_all(path)?;
-        Ok(())
-    } else {
-        std::fs::remove_file(path)?;
-        Ok(())
+        plan.remove_dir_all(path)?;
+        return Ok(());
+    }
+    plan.remove_file(path)?;
+    Ok(())
+}
+
+/// Detaches `target` from the general directory `source`. A symlink into `source` is replaced with
+/// an empty directory and mirrored entries are removed, everything else is left untouched. With
+/// `restore` the models are copied back so the frontend keeps working on its own.
+pub fn unlink_directory(source: &Path, target: &Path, restore: bool, plan: &mut Plan) -> Result<()> {
+    match link_state(source, target)? {
+        LinkState::Symlink { into_general: true, .. } => {
+            debug!("Removing link {}", target.display());
+            remove_symlink(target, plan)?;
+            plan.create_dir_all(target)?;
+        }
+        LinkState::Symlink { points_to, into_general: false } => {
+            warn!(
+                "Not removing {} because it points to {} outside the general directory",
+                target.display(),
+                points_to.display()
+            );
+            return Ok(());
+        }
+        LinkState::Mirrored { .. } => {
+            for (managed, fingerprint) in read_manifest(target)? {
+                let managed_path = target.join(managed);
+                if is_still_managed(&managed_path, source, fingerprint.as_ref()) {
+                    plan.remove_file(&managed_path)?;
+                    remove_empty_parents(&managed_path, target, plan)?;
+                } else if managed_path.symlink_metadata().is_ok() {
+                    warn!("Keeping {}, it was replaced since it was linked", managed_path.display());
+                }
+            }
+            plan.remove_file(target.join(MANIFEST_FILE_NAME))?;
+        }
+        LinkState::Missing | LinkState::Directory { .. } | LinkState::File => return Ok(()),
+    }
+
+    if restore && source.is_dir() {
+        for file in collect_files(source)? {
+            let Ok(relative) = file.strip_prefix(source) else {
+                return Err(LinkError::InvalidPath(file.display().to_string()));
+            };
+            let destination = target.join(relative);
+            if destination.symlink_metadata().is_ok() && !plan.is_removed(&destination) {
+                warn!("Not restoring {} over an existing file", destination.display());
+                continue;
+            }
+            ensure_parent_directory(&destination, plan)?;
+            plan.copy(&file, &destination)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves every file from `from` into the same relative location under `into`. Files whose
+/// contents already exist somewhere under `into` are deleted instead of moved, as are links into
+/// `into`. Links to anywhere else are recreated under `into`, so the models they point to stay
+/// visible through the new link.
+fn migrate_directory(from: &Path, into: &Path, store: &mut Store, plan: &mut Plan) -> Result<()> {
+    info!("Migrating {} into {}", from.display(), into.display());
+
+    // Files already in the library, by size, so only same-sized files ever get hashed
+    let mut existing_by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    if into.is_dir() {
+        for file in collect_files(into)? {
+            // Links in the library may dangle and are never what a migrated file duplicates
+            let metadata = file.symlink_metadata()?;
+            if !metadata.is_symlink() {
+                existing_by_size.entry(metadata.len()).or_default().push(file);
+            }
+        }
+    }
+
+    for file in collect_files(from)? {
+        // Our own bookkeeping and the placeholders frontends ship are not user data
+        let is_manifest = file.file_name().is_some_and(|name| name == MANIFEST_FILE_NAME);
+        if is_manifest || is_placeholder(&file) {
+            plan.remove_file(&file)?;
+            continue;
+        }
+
+        let Ok(relative) = file.strip_prefix(from) else {
+            return Err(LinkError::InvalidPath(file.display().to_string()));
+        };
+
+        if file.symlink_metadata()?.is_symlink() {
+            let points_to = link_destination(&file)?;
+            if !is_inside(&points_to, into) {
+                let destination = free_path(&into.join(relative), plan);
+                info!("Moving link {} to {}", file.display(), destination.display());
+                ensure_parent_directory(&destination, plan)?;
+                plan.symlink(&points_to, &destination)?;
+            }
+            plan.remove_file(&file)?;
+            continue;
+        }
+
+        let size = file.metadata()?.len();
+        let candidates = existing_by_size.get(&size).cloned().unwrap_or_default();
+
+        let mut duplicate_of = None;
+        if !candidates.is_empty() {
+            let hash = file_hash(&file, store)?;
+            for candidate in candidates {
+                if file_hash(&candidate, store)? == hash {
+                    duplicate_of = Some(candidate);
+                    break;
+                }
+            }
+        }
+
+        if let Some(existing) = duplicate_of {
+            debug!("{} is a duplicate of {}", file.display(), existing.display());
+            plan.remove_file(&file)?;
+            continue;
+        }
+
+        let destination = free_path(&into.join(relative), plan);
+        ensure_parent_directory(&destination, plan)?;
+        plan.rename(&file, &destination)?;
+
+        // Later files are compared against the migrated one, which is still readable at its
+        // old location during a dry run
+        let readable = if plan.is_dry_run() { file.clone() } else { destination };
+        existing_by_size.entry(size).or_default().push(readable);
+    }
+
+    Ok(())
+}
+
+/// Moves a single frontend file that is in the way of `source` into the general directory next
+/// to it, or deletes it if it has the same contents as `source`.
+fn migrate_file(file: &Path, source: &Path, store: &mut Store, plan: &mut Plan) -> Result<()> {
+    if source.is_file() && file_hash(file, store)? == file_hash(source, store)? {
+        debug!("{} is a duplicate of {}", file.display(), source.display());
+        plan.remove_file(file)?;
+        return Ok(());
+    }
+
+    let destination = free_path(source, plan);
+    info!("Migrating {} to {}", file.display(), destination.display());
+    ensure_parent_directory(&destination, plan)?;
+    plan.rename(file, &destination)?;
+    Ok(())
+}
+
+/// The SHA256 of `file`, taken from the store while the file is unchanged since it was hashed.
+fn file_hash(file: &Path, store: &mut Store) -> Result<String> {
+    if let Some(hash) = store.lookup_hash(file)? {
+        return Ok(hash);
+    }
+    let hash = EldenRing::from_file(file)?;
+    store.insert_hashes(file, &ModelHashes::from_sha256(hash.clone()))?;
+    Ok(hash)
+}
+
+/// Whether `file` is one of the empty `put_*_here` files ComfyUI ships in its model folders.
+fn is_placeholder(file: &Path) -> bool {
+    let is_placeholder_name = file
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .is_some_and(|name| name.starts_with("put_") && name.ends_with("_here"));
+    is_placeholder_name && file.symlink_metadata().is_ok_and(|m| m.is_file() && m.len() == 0)
+}
+
+/// Where the symlink `link` points to, with a relative target resolved against its directory.
+fn link_destination(link: &Path) -> Result<PathBuf> {
+    let points_to = std::fs::read_link(link)?;
+    match link.parent() {
+        Some(parent) if points_to.is_relative() => Ok(parent.join(points_to)),
+        _ => Ok(points_to),
+    }
+}
+
+/// Whether `path` is inside `dir`, as written or once symlinks are resolved.
+fn is_inside(path: &Path, dir: &Path) -> bool {
+    path.starts_with(dir) || path.canonicalize().is_ok_and(|p| p.starts_with(dir))
+}
+
+/// Returns `path`, or the first `name (n).ext` next to it that is free once the plan is applied,
+/// so a dry run does not plan two files onto the same name either.
+pub fn free_path(path: &Path, plan: &Plan) -> PathBuf {
+    // Dangling links take up their name as well
+    let is_taken = |path: &Path| plan.exists(path) || (!plan.is_removed(path) && path.symlink_metadata().is_ok());
+    if !is_taken(path) {
+        return path.to_path_buf();
     }
+
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let extension = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+
+    let free = (1..)
+        .map(|index| path.with_file_name(format!("{} ({}){}", stem, index, extension)))
+        .find(|candidate| !is_taken(candidate))
+        .unwrap_or_else(|| path.to_path_buf());
+    info!("{} exists, using {}", path.display(), free.display());
+    free
 }
 
-fn ensure_parent_directory(path: &std::path::Path) -> Result<()> {
-    if let Some(parent) = path.parent() {
-        if !parent.exists() {
-            debug!("Creating parent directory: {}", parent.display());
-            std::fs::create_dir_all(parent)?;
+/// Recursively lists all files under `dir` without following symlinked directories.
+pub fn collect_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    if !dir.symlink_metadata()?.is_dir() {
+        files.push(dir.to_path_buf());
+        return Ok(files);
+    }
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            files.extend(collect_files(&entry.path())?);
+        } else {
+            files.push(entry.path());
         }
     }
+
+    Ok(files)
+}
+
+fn ensure_parent_directory(path: &std::path::Path, plan: &mut Plan) -> Result<()> {
+    if let Some(parent) = path.parent()
+        && (!parent.exists() || plan.is_removed(parent))
+    {
+        debug!("Creating parent directory: {}", parent.display());
+        plan.create_dir_all(parent)?;
+    }
     Ok(())
 }
 
-fn create_platform_specific_symlink(source: &std::path::Path, target: &std::path::Path) -> Result<()> {
+pub fn create_platform_specific_symlink(source: &std::path::Path, target: &std::path::Path) -> Result<()> {
     #[cfg(windows)]
     {
         if source.is_dir() {
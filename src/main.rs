@@ -2,7 +2,15 @@ mod api;
 mod civitai;
 mod configuration;
 mod hash;
+mod inbox;
+mod interrupt;
 mod link;
+mod plan;
+mod safetensors;
+mod sidecar;
+mod store;
+mod template;
+mod webui_cache;
 
 use std::path::PathBuf;
 use std::process::exit;
@@ -12,12 +20,30 @@ use log::info;
 use log::LevelFilter;
 use structopt::StructOpt;
 
+use crate::api::detect_model;
+use crate::api::get_model_hash;
+use crate::api::get_model_hashes;
+use crate::api::get_library_models;
+use crate::api::get_model_info;
+use crate::api::get_orphan_models;
+use crate::api::get_settled_orphan_models;
+use crate::api::hash_models;
+use crate::api::print_status;
 use crate::api::process_comfyui;
+use crate::api::process_unlink;
 use crate::api::process_webui;
 use crate::api::sort_models;
+use crate::api::verify_library;
+use crate::api::write_model_sidecars;
+use crate::civitai::CivitAiClient;
 use crate::configuration::Config;
+use crate::configuration::DetectionOrder;
 use crate::configuration::FolderStructure;
-use crate::configuration::GeneralConfig;
+use crate::hash::HashOptions;
+use crate::link::LinkMode;
+use crate::link::ReplacePolicy;
+use crate::plan::Plan;
+use crate::store::Store;
 
 #[derive(StructOpt, Debug)]
 #[structopt(
@@ -25,25 +51,115 @@ use crate::configuration::GeneralConfig;
     about = "Sync models between general directory and ComfyUI or WebUI"
 )]
 struct Args {
-    /// Path to general models directory
+    /// Path to general models directory, overrides `[general] path` from the config file
     #[structopt(parse(from_os_str))]
-    general: PathBuf,
+    general: Option<PathBuf>,
 
     /// Set logging verbosity level
-    #[structopt(short, long, default_value = "0")]
+    #[structopt(short, long, default_value = "0", global = true)]
     verbosity: u8,
 
     /// Optional path to config file
-    #[structopt(short, long)]
+    #[structopt(short, long, global = true)]
     toml_config: Option<PathBuf>,
 
     /// Optional path to comfyui models directory
-    #[structopt(short, long)]
+    #[structopt(short, long, global = true)]
     comfyui: Option<PathBuf>,
 
     /// Optional path to webui models directory
-    #[structopt(short, long)]
+    #[structopt(short, long, global = true)]
     webui: Option<PathBuf>,
+
+    /// Print the planned moves, links and deletions without touching the filesystem
+    #[structopt(long, global = true)]
+    dry_run: bool,
+
+    /// Move files found in frontend model directories into the general directory before linking
+    #[structopt(long, conflicts_with = "force", global = true)]
+    migrate: bool,
+
+    /// Delete files found in frontend model directories before linking
+    #[structopt(long, global = true)]
+    force: bool,
+
+    /// How models are exposed to the frontends: symlink, mirror, hardlink, reflink or copy.
+    /// Overrides `link_mode` from the config file
+    #[structopt(long, global = true)]
+    link_mode: Option<LinkMode>,
+
+    /// Where the type of orphan models comes from: civitai-first, header-first or header-only.
+    /// Overrides `[sort] detection` from the config file
+    #[structopt(long, global = true)]
+    detection: Option<DetectionOrder>,
+
+    /// Ask CivitAI again instead of using cached answers
+    #[structopt(long, conflicts_with = "offline", global = true)]
+    refresh: bool,
+
+    /// Never contact CivitAI, cached answers are used regardless of their age
+    #[structopt(long, global = true)]
+    offline: bool,
+
+    /// Number of files hashed at the same time, overrides `[hash] jobs` from the config file
+    #[structopt(short, long, global = true)]
+    jobs: Option<usize>,
+
+    /// Step to run, defaults to `sync`
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug, PartialEq)]
+enum Command {
+    /// Move orphan models from the general directory root and the inboxes into their category
+    /// folders
+    Sort,
+    /// Link the general directory into the enabled frontends
+    Link,
+    /// Sort orphan models, then link the general directory into the enabled frontends
+    Sync,
+    /// Print SHA256 hashes of model files, defaults to the orphans in the general directory
+    Hash {
+        #[structopt(parse(from_os_str))]
+        files: Vec<PathBuf>,
+        /// Print every hash CivitAI lists: AutoV1, AutoV2, SHA256, CRC32, BLAKE3 and AutoV3
+        #[structopt(long)]
+        all: bool,
+    },
+    /// Hash every model in the library again and compare with the stored hashes and CivitAI,
+    /// exits with an error when files are corrupted, truncated or missing
+    Verify {
+        /// Forget the hashes of missing files after reporting them
+        #[structopt(long)]
+        prune: bool,
+    },
+    /// Take over the SHA256 hashes the WebUI cached in its `cache.json`, so they are not calculated again
+    ImportHashes,
+    /// Print the CivitAI information of a model file
+    Info {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Write CivitAI metadata next to model files, defaults to every model in the general directory
+    Metadata {
+        #[structopt(parse(from_os_str))]
+        files: Vec<PathBuf>,
+        /// Replace existing `.json` metadata, which may contain edits made in the WebUI, and previews
+        #[structopt(long)]
+        overwrite: bool,
+        /// Also download preview images, regardless of `[sort] previews`
+        #[structopt(long)]
+        previews: bool,
+    },
+    /// Show how frontend directories are linked and how many orphans wait to be sorted
+    Status,
+    /// Replace links into the general directory with real directories in the enabled frontends
+    Unlink {
+        /// Copy the models back into the frontend directories
+        #[structopt(long)]
+        restore: bool,
+    },
 }
 
 fn setup_logger(verbosity: u8) -> Result<(), Box<dyn std::error::Error>> {
@@ -82,44 +198,175 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         exit(0);
     };
 
-    let general_path = parsed_args.general.canonicalize()?;
-    info!("General path: {}", general_path.display());
+    setup_logger(parsed_args.verbosity)?;
+    interrupt::install_handler()?;
 
-    let config: Option<Config> = parsed_args.toml_config.map(|path| {
-        let config_data = std::fs::read_to_string(&path).unwrap_or_default();
-        toml::from_str(&config_data).unwrap()
-    });
+    let mut config: Config = match &parsed_args.toml_config {
+        Some(path) => Config::from_file(path)?,
+        None => Config::default(),
+    };
+    debug!("Current config: {:?}", config);
 
-    let comfyui_path = if let Some(c) = config.as_ref() {
-        Some(c.comfyui.path.clone())
-    } else {
-        parsed_args.comfyui
+    let general = config.resolve_general(parsed_args.general)?;
+    if let Some(general) = &general {
+        info!("General path: {}", general.path.display());
+    }
+    let mut store = match &general {
+        Some(general) => Store::open(&general.path)?.with_dry_run(parsed_args.dry_run),
+        None => Store::in_memory(),
     };
 
-    let webui_path = if let Some(c) = config.as_ref() {
-        Some(c.webui.path.clone())
-    } else {
-        parsed_args.webui
+    let command = parsed_args.command.unwrap_or(Command::Sync);
+    let client = CivitAiClient::from_config(&config.civitai)?
+        .with_refresh(parsed_args.refresh)
+        .with_offline(parsed_args.offline);
+    debug!("CivitAI API: {}", client.base_url());
+
+    let hash_options = HashOptions {
+        jobs: parsed_args.jobs.or(config.hash.jobs).unwrap_or_else(hash::default_jobs),
+        buffer_size: config.hash.buffer_kb * 1024,
     };
 
-    let verbosity = parsed_args.verbosity;
+    if let Command::Info { file } = &command {
+        if let Ok((model_type, base_model)) = detect_model(file) {
+            println!("Detected from header: {} ({})", model_type, base_model);
+        }
+        let model_info = get_model_info(file, &mut store, &client)?;
+        println!("{}", model_info);
+        store.save()?;
+        return Ok(());
+    }
+
+    let Some(general) = general else {
+        return Err("No general models directory provided".into());
+    };
 
-    if comfyui_path.is_none() && webui_path.is_none() && config.is_none() {
+    if let Command::Hash { files, all } = &command {
+        let files = if files.is_empty() {
+            get_orphan_models(&general.path, &config.inbox)?
+        } else {
+            files.clone()
+        };
+        hash_models(&files, &mut store, hash_options, *all)?;
+        for file in files {
+            if !all {
+                println!("{}  {}", get_model_hash(&file, &mut store)?, file.display());
+                continue;
+            }
+            let hashes = get_model_hashes(&file, &mut store)?;
+            println!("{}", file.display());
+            for (name, hash) in hashes.all() {
+                println!("  {:<7} {}", name, hash);
+            }
+        }
+        store.save()?;
+        return Ok(());
+    }
+
+    let mut comfyui = config.resolve_comfyui(parsed_args.comfyui);
+    let mut webui = config.resolve_webui(parsed_args.webui);
+
+    if let Some(link_mode) = parsed_args.link_mode {
+        comfyui.iter_mut().for_each(|c| c.link_mode = link_mode);
+        webui.iter_mut().for_each(|w| w.link_mode = link_mode);
+    }
+
+    if command == Command::ImportHashes {
+        let Some(webui) = &webui else {
+            return Err("No WebUI path provided".into());
+        };
+        println!("{}", webui_cache::import_hashes(webui, &mut store)?);
+        store.save()?;
+        return Ok(());
+    }
+
+    let links = matches!(command, Command::Link | Command::Sync | Command::Unlink { .. });
+    if links && comfyui.is_none() && webui.is_none() && parsed_args.toml_config.is_none() {
         return Err("No paths provided".into());
     }
 
-    setup_logger(verbosity)?;
+    let policy = if parsed_args.force {
+        ReplacePolicy::Force
+    } else if parsed_args.migrate {
+        ReplacePolicy::Migrate
+    } else {
+        ReplacePolicy::Abort
+    };
+    let mut plan = Plan::new(parsed_args.dry_run);
+
+    if let Command::Metadata {
+        files,
+        overwrite,
+        previews,
+    } = &command
+    {
+        let files = if files.is_empty() {
+            get_library_models(&general.path)?
+        } else {
+            files.clone()
+        };
+        hash_models(&files, &mut store, hash_options, false)?;
+        let mut sort = config.sort.clone();
+        sort.sidecars = true;
+        sort.previews |= previews;
+        write_model_sidecars(&files, &mut store, &client, &sort, *overwrite, &mut plan)?;
+    }
 
-    if let Some(cfg) = &config {
-        debug!("Current config: {:?}", cfg);
+    let models_structure: FolderStructure = general.clone().into();
+    if matches!(command, Command::Sort | Command::Sync) {
+        let mut sort = config.sort.clone();
+        sort.detection = parsed_args.detection.unwrap_or(sort.detection);
+        let orphans = get_settled_orphan_models(&general.path, &config.inbox)?;
+        if sort.detection == DetectionOrder::CivitaiFirst {
+            hash_models(&orphans, &mut store, hash_options, false)?;
+        }
+        sort_models(&orphans, &models_structure, &mut store, &client, &sort, &mut plan)?;
     }
 
-    sort_models(general_path.clone())?;
+    let general_path = general.path.clone();
 
-    let models_structure: FolderStructure = GeneralConfig::new(general_path).into();
+    if let Command::Verify { prune } = command {
+        let report = verify_library(&models_structure, &mut store, &client, hash_options, prune)?;
+        for (path, issue) in &report.issues {
+            println!("{}: {}", path.display(), issue);
+        }
+        if !report.issues.is_empty() {
+            return Err(format!("{} problems found while verifying {} models", report.issues.len(), report.verified).into());
+        }
+        println!("Verified {} models, no problems found", report.verified);
+        return Ok(());
+    }
 
-    process_comfyui(&models_structure, &config, comfyui_path)?;
-    process_webui(&models_structure, &config, webui_path)?;
+    if command == Command::Status {
+        if let Some(comfyui) = comfyui {
+            print_status("ComfyUI", &models_structure, &comfyui.try_into()?)?;
+        }
+        if let Some(webui) = webui {
+            print_status("WebUI", &models_structure, &webui.try_into()?)?;
+        }
+        let orphan_count = get_orphan_models(&general_path, &config.inbox)?.len();
+        println!("Orphan models waiting to be sorted: {}", orphan_count);
+        return Ok(());
+    }
+
+    if let Command::Unlink { restore } = command {
+        if let Some(comfyui) = comfyui {
+            process_unlink(&models_structure, &comfyui.try_into()?, restore, &mut store, &mut plan)?;
+        }
+        if let Some(webui) = webui {
+            process_unlink(&models_structure, &webui.try_into()?, restore, &mut store, &mut plan)?;
+        }
+    } else if links {
+        process_comfyui(&models_structure, comfyui, policy, &mut store, &mut plan)?;
+        process_webui(&models_structure, webui, policy, &mut store, &mut plan)?;
+    }
+    store.save()?;
+
+    if plan.is_dry_run() {
+        println!("{}", plan);
+    } else {
+        info!("Applied {} filesystem actions", plan.actions().len());
+    }
 
     Ok(())
 }
@@ -127,10 +374,37 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 #[cfg(test)]
 mod tests {
     use std::io::BufReader;
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    use structopt::StructOpt;
 
+    use crate::api;
     use crate::civitai::ModelInfo;
-    use crate::civitai::API_URL;
+    use crate::civitai::ModelType;
+    use crate::configuration::Config;
+    use crate::configuration::ConfigError;
+    use crate::configuration::FolderStructure;
+    use crate::configuration::GeneralConfig;
+    use crate::hash;
     use crate::hash::EldenRing;
+    use crate::hash::Fingerprint;
+    use crate::hash::HashOptions;
+    use crate::link::LinkError;
+    use crate::link::LinkMode;
+    use crate::link::ReplacePolicy;
+    use crate::plan::Action;
+    use crate::plan::Plan;
+    use crate::safetensors;
+    use crate::safetensors::Header;
+    use crate::sidecar;
+    use crate::sidecar::LoraMetadata;
+    use crate::store;
+    use crate::store::Store;
+    use crate::template;
+    use crate::template::TemplateError;
+    use crate::Args;
+    use crate::Command;
 
     #[test]
     fn test_eldenring_hash() {
@@ -141,40 +415,588 @@ mod tests {
     }
 
     #[test]
-    fn test_civitai_query() {
-        let example_hash = "EA88289D69C4F091627B4192F9F5EA8467A893D028B6ED8D8E055F135B22A1F2";
-        let url = format!("{}{}", API_URL, example_hash);
+    fn test_model_hashes() {
+        let sha256 = |data: &[u8]| EldenRing::calculate_hash_sha256(data).unwrap();
+
+        // Crosses the AutoV1 region and the end of the safetensors header with small reads
+        let header = br#"{"__metadata__":{}}"#;
+        let mut model = (header.len() as u64).to_le_bytes().to_vec();
+        model.extend_from_slice(header);
+        model.extend((0..0x120000u32).map(|index| (index % 251) as u8));
+
+        for buffer_size in [4096, 5000, hash::DEFAULT_BUFFER_SIZE] {
+            let hashes = EldenRing::calculate_hashes_with(model.as_slice(), true, buffer_size, |_| ()).unwrap();
+            assert_eq!(hashes.sha256, sha256(&model));
+            assert_eq!(hashes.auto_v2(), &hashes.sha256[..10]);
+            assert_eq!(hashes.auto_v1.as_deref(), Some(&sha256(&model[0x100000..0x110000])[..8]));
+            assert_eq!(hashes.auto_v3.as_deref(), Some(&sha256(&model[8 + header.len()..])[..12]));
+            assert_eq!(hashes.crc32, Some(format!("{:08X}", crc32fast::hash(&model))));
+            assert_eq!(hashes.blake3, Some(blake3::hash(&model).to_hex().to_uppercase()));
+        }
 
-        let first_response = reqwest::blocking::get(&url);
-        assert!(first_response.is_ok(), "couldn't get response");
-        let first_response = first_response.unwrap();
+        // Files shorter than the AutoV1 region hash the empty input, other formats have no AutoV3
+        let hashes = EldenRing::calculate_hashes_with(b"ckpt".as_slice(), false, 4096, |_| ()).unwrap();
+        assert_eq!(hashes.auto_v1.as_deref(), Some(&sha256(b"")[..8]));
+        assert_eq!(hashes.auto_v3, None);
+        assert_eq!(hashes.all().len(), 5);
+    }
 
-        let mut response_text: Result<String, reqwest::Error> = Ok(String::default());
+    #[test]
+    fn test_parallel_hashing() {
+        let dir = tempfile::tempdir().unwrap();
+        let files: Vec<PathBuf> = (0..5)
+            .map(|index| {
+                let file = dir.path().join(format!("model{}.safetensors", index));
+                std::fs::write(&file, vec![index as u8; 10_000 * (index + 1)]).unwrap();
+                file
+            })
+            .collect();
+
+        let options = HashOptions {
+            jobs: 3,
+            buffer_size: 4096,
+        };
+        let mut hashed = vec![];
+        hash::hash_files(&files, options, |file, result| hashed.push((file.to_path_buf(), result.unwrap())));
+
+        assert_eq!(hashed.len(), files.len());
+        for (file, hashes) in hashed {
+            assert_eq!(hashes.sha256, EldenRing::from_file(&file).unwrap());
+        }
 
-        match first_response.status() {
-            reqwest::StatusCode::OK => {
-                response_text = first_response.text();
-            }
-            reqwest::StatusCode::NOT_FOUND => {
-                println!("Warning: model not found");
-            }
-            reqwest::StatusCode::SERVICE_UNAVAILABLE => {
-                println!("Warning: service unavailable");
-            }
-            _ => {
-                println!("Warning: couldn't get response");
+        let mut store = Store::open(dir.path()).unwrap();
+        api::hash_models(&files, &mut store, options, true).unwrap();
+        assert!(files.iter().all(|file| store.lookup_hash(file).unwrap().is_some()));
+    }
+
+    #[test]
+    fn test_hash_cache_revalidation() {
+        let dir = tempfile::tempdir().unwrap();
+        let model = dir.path().join("model.safetensors");
+        std::fs::write(&model, b"first version").unwrap();
+
+        let mut store = Store::open(dir.path()).unwrap();
+        let first = api::get_model_hash(&model, &mut store).unwrap();
+        assert_eq!(store.lookup_hash(&model).unwrap(), Some(first.clone()));
+
+        // Saved in batches rather than for every hash, and when the store goes away
+        let store_path = dir.path().join(store::STORE_FILE_NAME);
+        assert!(!store_path.exists());
+        drop(store);
+        assert!(store_path.exists());
+        let mut store = Store::open(dir.path()).unwrap();
+
+        std::fs::write(&model, b"second, longer version").unwrap();
+        assert_eq!(store.lookup_hash(&model).unwrap(), None, "served a stale hash");
+        let second = api::get_model_hash(&model, &mut store).unwrap();
+        assert_ne!(first, second);
+
+        #[cfg(unix)]
+        {
+            let moved = dir.path().join("loras").join("model.safetensors");
+            std::fs::create_dir(moved.parent().unwrap()).unwrap();
+            std::fs::rename(&model, &moved).unwrap();
+            assert_eq!(store.lookup_hash(&moved).unwrap(), Some(second.clone()));
+            store.save().unwrap();
+
+            let saved = std::fs::read_to_string(dir.path().join(store::STORE_FILE_NAME)).unwrap();
+            assert!(saved.contains("\"loras/model.safetensors\""));
+            assert!(!saved.contains("\"model.safetensors\""));
+
+            // Renames are followed as well after opening the store again
+            drop(store);
+            let mut store = Store::open(dir.path()).unwrap();
+            let renamed = dir.path().join("loras").join("renamed.safetensors");
+            std::fs::rename(&moved, &renamed).unwrap();
+            assert_eq!(store.lookup_hash(&renamed).unwrap(), Some(second));
+            assert_eq!(store.library_files(), vec![renamed]);
+        }
+    }
+
+    #[test]
+    fn test_store_migrates_legacy_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let model = dir.path().join("model.safetensors");
+        std::fs::write(&model, b"model").unwrap();
+        let fingerprint = serde_json::to_value(Fingerprint::from_file(&model).unwrap()).unwrap();
+
+        let mut entry = fingerprint.as_object().unwrap().clone();
+        entry.insert("hash".to_string(), "CACHED".into());
+        let legacy = serde_json::json!({
+            model.to_string_lossy(): entry,
+            "/models/unvalidated.safetensors": "OLD",
+        });
+        let legacy_path = dir.path().join("orphan_cache.json");
+        std::fs::write(&legacy_path, legacy.to_string()).unwrap();
+
+        let mut store = Store::open(dir.path()).unwrap();
+        assert_eq!(store.lookup_hash(&model).unwrap(), Some("CACHED".to_string()));
+        store.save().unwrap();
+        assert!(!legacy_path.exists());
+
+        let saved: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(dir.path().join(store::STORE_FILE_NAME)).unwrap()).unwrap();
+        assert_eq!(saved["version"], 2);
+        assert_eq!(saved["hashes"].as_object().unwrap().len(), 1);
+
+        // Only the SHA256 was cached back then, the other hashes need another pass
+        let hashes = api::get_model_hashes(&model, &mut store).unwrap();
+        assert_ne!(hashes.sha256, "CACHED");
+        assert!(hashes.is_complete());
+
+        std::fs::write(dir.path().join(store::STORE_FILE_NAME), r#"{"version": 99}"#).unwrap();
+        assert!(Store::open(dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_config_sections() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [general]
+            path = "/models"
+
+            [general.config]
+            checkpoints = "Stable-diffusion"
+            loras = "Lora"
+            controlnet = "controlnet"
+            upscale_models = "upscale_models"
+            vae = "vae"
+            embeddings = "embeddings"
+
+            [comfyui]
+            enabled = false
+            path = "/comfyui/models"
+
+            [webui]
+            path = "/webui"
+
+            [inbox]
+            directories = ["/downloads"]
+            exclude = ["training/**"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.inbox.directories, [PathBuf::from("/downloads")]);
+        assert!(config.inbox.exclude[0].matches("training/lora.safetensors"));
+        assert_eq!(config.inbox.extensions.len(), 5);
+        assert!(toml::from_str::<Config>("[inbox]\ninclude = [\"[\"]").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let models = dir.path().join("models");
+        let downloads = dir.path().join("downloads");
+        std::fs::create_dir_all(models.join("inbox")).unwrap();
+        std::fs::create_dir(&downloads).unwrap();
+        config.inbox.directories = vec![dir.path().join("models/../downloads")];
+        let general = config.resolve_general(Some(models.clone())).unwrap().unwrap();
+        assert_eq!(general.path, models.canonicalize().unwrap());
+        assert_eq!(general.config.0["checkpoints"].as_str(), "Stable-diffusion");
+        assert_eq!(config.inbox.directories, [downloads.canonicalize().unwrap()]);
+        assert!(config.resolve_comfyui(None).is_none());
+        assert!(config.resolve_comfyui(Some("/other".into())).is_some());
+        assert!(config.resolve_webui(None).is_some());
+        assert!(Config::default().resolve_general(None).unwrap().is_none());
+
+        // Inboxes may be neither the general directory, inside it nor around it
+        let linked = dir.path().join("linked");
+        crate::link::create_platform_specific_symlink(&models, &linked).unwrap();
+        for inbox in [models.join("inbox"), dir.path().to_path_buf(), linked] {
+            config.inbox.directories = vec![inbox];
+            let result = config.resolve_general(Some(models.clone()));
+            assert!(matches!(result, Err(ConfigError::InboxOverlap(_))));
+        }
+    }
+
+    #[test]
+    fn test_model_categories() {
+        let config: Config = toml::from_str(
+            r#"
+            [webui]
+            path = "/webui"
+
+            [webui.config]
+            loras = "models/LyCORIS"
+            text_encoders = ""
+            "#,
+        )
+        .unwrap();
+        let webui = config.resolve_webui(None).unwrap();
+        assert_eq!(webui.config.0["loras"].as_str(), "models/LyCORIS");
+        assert_eq!(webui.config.0["checkpoints"].as_str(), "models/Stable-diffusion");
+        assert!(!webui.config.0.contains_key("text_encoders"));
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("loras")).unwrap();
+        let general: FolderStructure = GeneralConfig::new(dir.path()).into();
+        let webui: FolderStructure = webui.try_into().unwrap();
+        assert_eq!(general.directory("ipadapter"), dir.path().join("ipadapter"));
+        assert!(general.pairs(&webui).iter().all(|(from, _)| !from.ends_with("clip_vision")));
+
+        // Only categories the library has are linked
+        let mut plan = Plan::new(true);
+        general.soft_link_to(&webui, ReplacePolicy::Abort, &mut Store::in_memory(), &mut plan).unwrap();
+        let links: Vec<&Action> = plan.actions().iter().filter(|action| matches!(action, Action::Symlink { .. })).collect();
+        assert_eq!(
+            links,
+            [&Action::Symlink {
+                source: dir.path().join("loras"),
+                target: PathBuf::from("/webui/models/LyCORIS"),
+            }]
+        );
+
+        for (name, category) in [("VAE", "vae"), ("TextualInversion", "embeddings"), ("LoCon", "loras"), ("MotionModule", "animatediff_models")] {
+            let model_type: ModelType = serde_json::from_value(name.into()).unwrap();
+            assert_eq!(model_type.category(), Some(category));
+        }
+    }
+
+    #[test]
+    fn test_dry_run_plan() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("library");
+        let mut plan = Plan::new(true);
+        plan.create_dir_all(root.join("loras")).unwrap();
+        plan.create_dir_all(root.join("loras")).unwrap();
+        plan.rename(root.join("a.safetensors"), root.join("loras/a.safetensors"))
+            .unwrap();
+
+        plan.write_file(root.join("loras/a.preview.png"), [0; 4096]).unwrap();
+        // Names taken by planned moves are not handed out again
+        let moved = root.join("loras/a.safetensors");
+        assert_eq!(crate::link::free_path(&moved, &plan), root.join("loras/a (1).safetensors"));
+
+        assert!(!root.exists());
+        assert_eq!(plan.actions().len(), 3);
+        assert_eq!(plan.actions()[0], Action::CreateDir(root.join("loras")));
+        assert_eq!(
+            plan.actions()[2],
+            Action::WriteFile {
+                path: root.join("loras/a.preview.png"),
+                size: 4096
             }
+        );
+    }
+
+    #[test]
+    fn test_replace_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let general = root.join("general/loras");
+        let frontend = root.join("frontend/loras");
+        std::fs::create_dir_all(&general).unwrap();
+        std::fs::create_dir_all(frontend.join("sub")).unwrap();
+        std::fs::write(general.join("a.safetensors"), "same").unwrap();
+        std::fs::write(frontend.join("copy.safetensors"), "same").unwrap();
+        std::fs::write(frontend.join("sub/b.safetensors"), "new").unwrap();
+        std::fs::write(frontend.join("put_loras_here"), "").unwrap();
+        std::fs::write(root.join("elsewhere.safetensors"), "other drive").unwrap();
+        crate::link::create_platform_specific_symlink(&root.join("elsewhere.safetensors"), &frontend.join("elsewhere.safetensors")).unwrap();
+        crate::link::create_platform_specific_symlink(&general.join("a.safetensors"), &frontend.join("a.safetensors")).unwrap();
+        // A dangling link in the library must not stop the migration
+        crate::link::create_platform_specific_symlink(&root.join("gone.safetensors"), &general.join("gone.safetensors")).unwrap();
+
+        let mut store = Store::in_memory();
+        let mut plan = Plan::new(false);
+        let result = crate::link::create_symlink(&general, &frontend, ReplacePolicy::Abort, &mut store, &mut plan);
+        assert!(matches!(result, Err(LinkError::TargetNotEmpty(_))));
+        assert!(frontend.join("sub/b.safetensors").exists());
+
+        crate::link::create_symlink(&general, &frontend, ReplacePolicy::Migrate, &mut store, &mut plan).unwrap();
+        assert!(frontend.symlink_metadata().unwrap().is_symlink());
+        assert!(general.join("sub/b.safetensors").exists());
+        assert!(!general.join("copy.safetensors").exists());
+        assert!(!general.join("put_loras_here").exists());
+        assert!(!general.join("a (1).safetensors").exists());
+        // Links to models outside the library move into it, links into it are dropped
+        assert_eq!(std::fs::read_to_string(general.join("elsewhere.safetensors")).unwrap(), "other drive");
+        assert!(general.join("gone.safetensors").symlink_metadata().unwrap().is_symlink());
+
+        // A stock ComfyUI folder only holds its placeholder
+        let checkpoints = root.join("frontend/checkpoints");
+        std::fs::create_dir_all(&checkpoints).unwrap();
+        std::fs::write(checkpoints.join("put_checkpoints_here"), "").unwrap();
+        crate::link::create_symlink(&general, &checkpoints, ReplacePolicy::Abort, &mut store, &mut plan).unwrap();
+        assert!(checkpoints.symlink_metadata().unwrap().is_symlink());
+    }
+
+    #[test]
+    fn test_mirror_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let general = root.join("general/loras");
+        let frontend = root.join("frontend/loras");
+        std::fs::create_dir_all(general.join("sdxl")).unwrap();
+        std::fs::create_dir_all(&frontend).unwrap();
+        std::fs::write(general.join("sdxl/a.safetensors"), "model").unwrap();
+        std::fs::write(frontend.join("own.safetensors"), "own").unwrap();
+
+        let mut store = Store::in_memory();
+        let mut plan = Plan::new(false);
+        crate::link::mirror_directory(&general, &frontend, LinkMode::Copy, ReplacePolicy::Abort, &mut store, &mut plan)
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(frontend.join("sdxl/a.safetensors")).unwrap(), "model");
+        assert!(frontend.join("own.safetensors").exists());
+
+        let mut plan = Plan::new(false);
+        crate::link::mirror_directory(&general, &frontend, LinkMode::Copy, ReplacePolicy::Abort, &mut store, &mut plan)
+            .unwrap();
+        assert!(plan.actions().is_empty());
+        assert_eq!("HardLink".parse::<LinkMode>().unwrap(), LinkMode::Hardlink);
+
+        std::fs::remove_file(general.join("sdxl/a.safetensors")).unwrap();
+        let mut plan = Plan::new(false);
+        crate::link::mirror_directory(&general, &frontend, LinkMode::Copy, ReplacePolicy::Abort, &mut store, &mut plan)
+            .unwrap();
+        assert!(!frontend.join("sdxl").exists());
+        assert!(frontend.join("own.safetensors").exists());
+
+        // Files the user put where a stale entry was are not ours to prune
+        for mode in [LinkMode::Copy, LinkMode::Mirror] {
+            std::fs::write(general.join("b.safetensors"), "model").unwrap();
+            let mut plan = Plan::new(false);
+            crate::link::mirror_directory(&general, &frontend, mode, ReplacePolicy::Abort, &mut store, &mut plan)
+                .unwrap();
+            std::fs::remove_file(general.join("b.safetensors")).unwrap();
+            std::fs::remove_file(frontend.join("b.safetensors")).unwrap();
+            std::fs::write(frontend.join("b.safetensors"), "user's own").unwrap();
+
+            let mut plan = Plan::new(false);
+            crate::link::mirror_directory(&general, &frontend, mode, ReplacePolicy::Abort, &mut store, &mut plan)
+                .unwrap();
+            assert_eq!(std::fs::read_to_string(frontend.join("b.safetensors")).unwrap(), "user's own");
+            assert!(crate::link::read_manifest(&frontend).unwrap().is_empty());
+            std::fs::remove_file(frontend.join("b.safetensors")).unwrap();
         }
+    }
+
+    #[test]
+    fn test_unlink_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let general = root.join("general/vae");
+        let frontend = root.join("frontend/vae");
+        let foreign = root.join("frontend/foreign");
+        std::fs::create_dir_all(&general).unwrap();
+        std::fs::write(general.join("vae.safetensors"), "vae").unwrap();
+
+        let mut store = Store::in_memory();
+        let mut plan = Plan::new(false);
+        crate::link::create_symlink(&general, &frontend, ReplacePolicy::Abort, &mut store, &mut plan).unwrap();
+        crate::link::create_platform_specific_symlink(root, &foreign).unwrap();
+
+        crate::link::unlink_directory(&general, &frontend, true, &mut plan).unwrap();
+        crate::link::unlink_directory(&general, &foreign, true, &mut plan).unwrap();
+        assert!(!frontend.symlink_metadata().unwrap().is_symlink());
+        assert!(frontend.join("vae.safetensors").is_file());
+        assert!(general.join("vae.safetensors").is_file());
+        assert!(foreign.symlink_metadata().unwrap().is_symlink());
+    }
+
+    fn safetensors_bytes(header: serde_json::Value) -> Vec<u8> {
+        let header = serde_json::to_vec(&header).unwrap();
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend(header);
+        bytes
+    }
+
+    #[test]
+    fn test_safetensors_detection() {
+        let tensor = |shape: &[u64]| serde_json::json!({"dtype": "F16", "shape": shape, "data_offsets": [0, 0]});
+
+        let lora = safetensors_bytes(serde_json::json!({
+            "__metadata__": {"ss_sd_model_name": "ponyDiffusionV6XL.safetensors"},
+            "lora_unet_input_blocks_4_1_transformer_blocks_0_attn2_to_k.lora_down.weight": tensor(&[16, 2048]),
+            "lora_te2_text_model_encoder_layers_0_mlp_fc1.lora_up.weight": tensor(&[5120, 16]),
+        }));
+        let detection = safetensors::detect(&Header::from_reader(lora.as_slice()).unwrap()).unwrap();
+        assert_eq!(detection.model_type, ModelType::Lora);
+        assert_eq!(detection.base_model, "Pony");
+
+        let checkpoint = safetensors_bytes(serde_json::json!({
+            "model.diffusion_model.input_blocks.1.1.transformer_blocks.0.attn2.to_k.weight": tensor(&[320, 768]),
+            "first_stage_model.decoder.conv_in.weight": tensor(&[512, 4, 3, 3]),
+        }));
+        let detection = safetensors::detect(&Header::from_reader(checkpoint.as_slice()).unwrap()).unwrap();
+        assert_eq!(detection.model_type, ModelType::Checkpoint);
+        assert_eq!(detection.base_model, "SD 1.5");
+
+        let flux = safetensors_bytes(serde_json::json!({
+            "double_blocks.0.img_attn.qkv.weight": tensor(&[9216, 3072]),
+            "guidance_in.in_layer.weight": tensor(&[3072, 256]),
+        }));
+        let detection = safetensors::detect(&Header::from_reader(flux.as_slice()).unwrap()).unwrap();
+        assert_eq!(detection.base_model, "Flux.1 D");
+
+        let flux_schnell = safetensors_bytes(serde_json::json!({
+            "double_blocks.0.img_attn.qkv.weight": tensor(&[9216, 3072]),
+        }));
+        let detection = safetensors::detect(&Header::from_reader(flux_schnell.as_slice()).unwrap()).unwrap();
+        assert_eq!(detection.base_model, "Flux.1 S");
+
+        let flux_lora = safetensors_bytes(serde_json::json!({
+            "lora_unet_double_blocks_0_img_attn_qkv.lora_down.weight": tensor(&[16, 3072]),
+            "lora_unet_double_blocks_0_img_attn_qkv.lora_up.weight": tensor(&[9216, 16]),
+        }));
+        let detection = safetensors::detect(&Header::from_reader(flux_lora.as_slice()).unwrap()).unwrap();
+        assert_eq!(detection.model_type, ModelType::Lora);
+        assert_eq!(detection.base_model, "Flux.1 D");
+
+        let vae = safetensors_bytes(serde_json::json!({
+            "decoder.conv_in.weight": tensor(&[512, 4, 3, 3]),
+            "quant_conv.weight": tensor(&[8, 8, 1, 1]),
+        }));
+        let detection = safetensors::detect(&Header::from_reader(vae.as_slice()).unwrap()).unwrap();
+        assert_eq!(detection.model_type, ModelType::Vae);
+        assert_eq!(detection.base_model, "Other");
+
+        let unknown = safetensors_bytes(serde_json::json!({"weight": tensor(&[4, 4])}));
+        assert!(safetensors::detect(&Header::from_reader(unknown.as_slice()).unwrap()).is_none());
+    }
 
-        assert!(response_text.is_ok(), "couldn't get response text");
-        let text = response_text.unwrap();
+    #[test]
+    fn test_subcommands() {
+        let args = Args::from_iter_safe(["model_sync", "/models"]).unwrap();
+        assert_eq!(args.general, Some("/models".into()));
+        assert_eq!(args.command, None);
+
+        let args = Args::from_iter_safe(["model_sync", "/models", "link", "--dry-run"]).unwrap();
+        assert_eq!(args.command, Some(Command::Link));
+        assert!(args.dry_run);
+
+        let args = Args::from_iter_safe(["model_sync", "info", "model.safetensors"]).unwrap();
+        assert_eq!(args.general, None);
+        assert_eq!(
+            args.command,
+            Some(Command::Info {
+                file: "model.safetensors".into()
+            })
+        );
+
+        let args = Args::from_iter_safe(["model_sync", "/models", "metadata", "--overwrite"]).unwrap();
+        assert_eq!(
+            args.command,
+            Some(Command::Metadata {
+                files: vec![],
+                overwrite: true,
+                previews: false
+            })
+        );
+
+        let args = Args::from_iter_safe(["model_sync", "/models", "verify", "--prune"]).unwrap();
+        assert_eq!(args.command, Some(Command::Verify { prune: true }));
+    }
+
+    #[test]
+    fn test_sidecar_metadata() {
+        let text = include_str!(
+            "../tests/fixtures/civitai/model-versions/by-hash/4E96766BB0E2B9A556EF874B4EC5BFE468FD377F721378FB6256B4EDC5971775.json"
+        );
+        let info: ModelInfo = serde_json::from_str(text).unwrap();
+        let metadata = LoraMetadata::from(&info);
+        assert_eq!(metadata.sd_version, "SDXL");
+        assert_eq!(metadata.activation_text, "detailed");
+        assert_eq!(metadata.notes, "https://civitai.com/models/122359?modelVersionId=135867");
+
+        let model = Path::new("/models/loras/detail.safetensors");
+        assert_eq!(
+            sidecar::sidecar_path(model, ".civitai.info"),
+            Path::new("/models/loras/detail.civitai.info")
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let model = dir.path().join("detail.safetensors");
+        let metadata_path = dir.path().join("detail.json");
+        std::fs::write(&metadata_path, "{}").unwrap();
+
+        let mut plan = Plan::new(true);
+        sidecar::write_sidecars(&model, &info, false, &mut plan).unwrap();
+        assert_eq!(plan.actions().len(), 1, "kept the existing metadata");
+        assert!(plan.exists(&dir.path().join("detail.civitai.info")));
+
+        plan.remove_file(&metadata_path).unwrap();
+        assert!(!plan.exists(&metadata_path));
+        sidecar::write_sidecars(&model, &info, false, &mut plan).unwrap();
+        assert!(plan.exists(&metadata_path));
+    }
+
+    #[test]
+    fn test_civitai_fixture() {
+        let text = include_str!(
+            "../tests/fixtures/civitai/model-versions/by-hash/4E96766BB0E2B9A556EF874B4EC5BFE468FD377F721378FB6256B4EDC5971775.json"
+        );
         assert!(!text.is_empty(), "response text is empty");
 
-        let json_from_response_text: Result<ModelInfo, serde_json::Error> = serde_json::from_str(&text);
+        let json_from_response_text: Result<ModelInfo, serde_json::Error> = serde_json::from_str(text);
         assert!(
             json_from_response_text.is_ok(),
             "couldn't parse response into JSON: {}",
             json_from_response_text.err().unwrap()
         );
+        assert_eq!(json_from_response_text.unwrap().model_info.model_type, ModelType::Lora);
+    }
+
+    #[test]
+    fn test_unknown_civitai_types_and_fields() {
+        let text = include_str!(
+            "../tests/fixtures/civitai/model-versions/by-hash/4E96766BB0E2B9A556EF874B4EC5BFE468FD377F721378FB6256B4EDC5971775.json"
+        );
+        let mut value: serde_json::Value = serde_json::from_str(text).unwrap();
+        value["model"]["type"] = "Detection".into();
+        value["remixOf"] = serde_json::json!({"id": 1});
+
+        let info: ModelInfo = serde_json::from_value(value).unwrap();
+        assert_eq!(info.model_info.model_type, ModelType::Other("Detection".to_string()));
+        assert_eq!(info.model_info.model_type.category(), None);
+        assert_eq!(info.extra["remixOf"]["id"], 1);
+
+        let written = serde_json::to_value(&info).unwrap();
+        assert_eq!(written["model"]["type"], "Detection");
+        assert_eq!(written["remixOf"]["id"], 1);
+
+        let other: ModelType = serde_json::from_value("Other".into()).unwrap();
+        assert_eq!(other, ModelType::Other("Other".to_string()));
+    }
+
+    #[test]
+    fn test_destination_template() {
+        let values = [
+            ("type", "models/Lora"),
+            ("base_model", "SDXL 1.0"),
+            ("model_name", "Detail: Tweaker / XL?"),
+            ("creator", ".."),
+            ("file_name", "lora.safetensors"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name, value.to_string()))
+        .collect();
+
+        let path = template::render("{type}/{base_model:lower}/{file_name}", &values).unwrap();
+        assert_eq!(path, Path::new("models/Lora/sdxl 1.0/lora.safetensors"));
+        let path = template::render("{type}//{creator}/{model_name}/{version_name}/{file_name}", &values).unwrap();
+        assert_eq!(path, Path::new("models/Lora/unknown/Detail_ Tweaker _ XL_/unknown/lora.safetensors"));
+
+        assert_eq!(template::sanitize(" con.txt "), "_con.txt");
+        assert_eq!(template::sanitize("v1.0. "), "v1.0");
+        assert_eq!(template::sanitize("a\\b\tc"), "a_b_c");
+
+        assert_eq!(
+            template::render("{type}/{author}", &values),
+            Err(TemplateError::UnknownPlaceholder("author".to_string()))
+        );
+        assert_eq!(
+            template::render("{type:upper}", &values),
+            Err(TemplateError::UnknownModifier("upper".to_string()))
+        );
+        assert!(matches!(template::render("{type}/{file_name", &values), Err(TemplateError::Unclosed(_))));
+        assert!(matches!(template::render("../{file_name}", &values), Err(TemplateError::InvalidPath(_))));
+        assert_eq!(template::placeholders("{type}/{creator:lower}").unwrap(), ["type", "creator"]);
+
+        assert!(template::check_destination("{type}/{creator}/{model_name} {file_name:lower}").is_ok());
+        assert_eq!(
+            template::check_destination("{type}/{base_model}"),
+            Err(TemplateError::MissingFileName("{type}/{base_model}".to_string()))
+        );
+        assert!(template::check_destination("{type}/{file_name}/").is_err());
+        assert!(toml::from_str::<Config>("[sort]\ndestination = \"{type}/{base_model}\"").is_err());
     }
 }
@@ -0,0 +1,224 @@
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use log::debug;
+
+use crate::link;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    CreateDir(PathBuf),
+    Rename { from: PathBuf, to: PathBuf },
+    RemoveFile(PathBuf),
+    RemoveDir(PathBuf),
+    Symlink { source: PathBuf, target: PathBuf },
+    HardLink { source: PathBuf, target: PathBuf },
+    Reflink { source: PathBuf, target: PathBuf },
+    Copy { source: PathBuf, target: PathBuf },
+    /// Only the size is kept, the contents are written right away and never held by the plan.
+    WriteFile { path: PathBuf, size: u64 },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::CreateDir(path) => write!(f, "mkdir     {}", path.display()),
+            Action::Rename { from, to } => write!(f, "move      {} -> {}", from.display(), to.display()),
+            Action::RemoveFile(path) => write!(f, "remove    {}", path.display()),
+            Action::RemoveDir(path) => write!(f, "remove    {} (recursive)", path.display()),
+            Action::Symlink { source, target } => {
+                write!(f, "symlink   {} -> {}", target.display(), source.display())
+            }
+            Action::HardLink { source, target } => {
+                write!(f, "hardlink  {} -> {}", target.display(), source.display())
+            }
+            Action::Reflink { source, target } => {
+                write!(f, "reflink   {} -> {}", target.display(), source.display())
+            }
+            Action::Copy { source, target } => write!(f, "copy      {} -> {}", source.display(), target.display()),
+            Action::WriteFile { path, size } => write!(f, "write     {} ({} bytes)", path.display(), size),
+        }
+    }
+}
+
+/// Records every filesystem mutation made during a run. Actions are applied immediately unless
+/// the plan is a dry run, in which case they are only collected so they can be printed.
+#[derive(Debug, Default)]
+pub struct Plan {
+    dry_run: bool,
+    actions: Vec<Action>,
+}
+
+impl Plan {
+    pub fn new(dry_run: bool) -> Self {
+        Self {
+            dry_run,
+            actions: vec![],
+        }
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn actions(&self) -> &[Action] {
+        &self.actions
+    }
+
+    pub fn create_dir_all<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
+        let path = path.as_ref().to_path_buf();
+        if self.actions.contains(&Action::CreateDir(path.clone())) {
+            return Ok(());
+        }
+        self.apply(Action::CreateDir(path))
+    }
+
+    pub fn rename<P: AsRef<Path>>(&mut self, from: P, to: P) -> std::io::Result<()> {
+        self.apply(Action::Rename {
+            from: from.as_ref().into(),
+            to: to.as_ref().into(),
+        })
+    }
+
+    pub fn remove_file<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
+        self.apply(Action::RemoveFile(path.as_ref().into()))
+    }
+
+    pub fn remove_dir_all<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
+        self.apply(Action::RemoveDir(path.as_ref().into()))
+    }
+
+    pub fn symlink<P: AsRef<Path>>(&mut self, source: P, target: P) -> std::io::Result<()> {
+        self.apply(Action::Symlink {
+            source: source.as_ref().into(),
+            target: target.as_ref().into(),
+        })
+    }
+
+    pub fn hard_link<P: AsRef<Path>>(&mut self, source: P, target: P) -> std::io::Result<()> {
+        self.apply(Action::HardLink {
+            source: source.as_ref().into(),
+            target: target.as_ref().into(),
+        })
+    }
+
+    pub fn reflink<P: AsRef<Path>>(&mut self, source: P, target: P) -> std::io::Result<()> {
+        self.apply(Action::Reflink {
+            source: source.as_ref().into(),
+            target: target.as_ref().into(),
+        })
+    }
+
+    pub fn copy<P: AsRef<Path>>(&mut self, source: P, target: P) -> std::io::Result<()> {
+        self.apply(Action::Copy {
+            source: source.as_ref().into(),
+            target: target.as_ref().into(),
+        })
+    }
+
+    pub fn write_file<P: AsRef<Path>, C: AsRef<[u8]>>(&mut self, path: P, contents: C) -> std::io::Result<()> {
+        let contents = contents.as_ref();
+        let action = Action::WriteFile {
+            path: path.as_ref().into(),
+            size: contents.len() as u64,
+        };
+        if !self.dry_run {
+            debug!("Applying: {}", action);
+            std::fs::write(path, contents)?;
+        }
+
+        self.actions.push(action);
+        Ok(())
+    }
+
+    /// Whether `path` was removed by this plan and not recreated since, which matters during a dry
+    /// run where it still exists.
+    pub fn is_removed(&self, path: &Path) -> bool {
+        for action in self.actions.iter().rev() {
+            match action {
+                Action::RemoveFile(removed) | Action::RemoveDir(removed) if path.starts_with(removed) => return true,
+                Action::Rename { from, .. } if path == from => return true,
+                Action::CreateDir(created) if created.starts_with(path) => return false,
+                _ => (),
+            }
+        }
+        false
+    }
+
+    /// Whether `path` exists once the plan is applied, taking planned writes, moves and removals
+    /// into account during a dry run.
+    pub fn exists(&self, path: &Path) -> bool {
+        for action in self.actions.iter().rev() {
+            match action {
+                Action::RemoveFile(removed) | Action::RemoveDir(removed) if path.starts_with(removed) => return false,
+                Action::Rename { from, .. } if path == from => return false,
+                Action::Rename { to: created, .. }
+                | Action::Symlink { target: created, .. }
+                | Action::HardLink { target: created, .. }
+                | Action::Reflink { target: created, .. }
+                | Action::Copy { target: created, .. }
+                | Action::WriteFile { path: created, .. }
+                    if path == created =>
+                {
+                    return true;
+                }
+                Action::CreateDir(created) if created.starts_with(path) => return true,
+                _ => (),
+            }
+        }
+        path.exists()
+    }
+
+    fn apply(&mut self, action: Action) -> std::io::Result<()> {
+        if !self.dry_run {
+            debug!("Applying: {}", action);
+            match &action {
+                Action::CreateDir(path) => std::fs::create_dir_all(path)?,
+                Action::Rename { from, to } => rename_or_copy(from, to)?,
+                Action::RemoveFile(path) => std::fs::remove_file(path)?,
+                Action::RemoveDir(path) => std::fs::remove_dir_all(path)?,
+                Action::Symlink { source, target } => link::create_platform_specific_symlink(source, target)?,
+                Action::HardLink { source, target } => std::fs::hard_link(source, target)?,
+                Action::Reflink { source, target } => {
+                    if reflink_copy::reflink_or_copy(source, target)?.is_some() {
+                        debug!("Reflinks not supported for {}, copied instead", target.display());
+                    }
+                }
+                Action::Copy { source, target } => {
+                    std::fs::copy(source, target)?;
+                }
+                Action::WriteFile { .. } => unreachable!("files are written by write_file"),
+            }
+        }
+
+        self.actions.push(action);
+        Ok(())
+    }
+}
+
+/// Renames `from` to `to`, falling back to copy and delete when they are on different filesystems.
+fn rename_or_copy(from: &Path, to: &Path) -> std::io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            debug!("{} is on another filesystem, copying instead", to.display());
+            std::fs::copy(from, to)?;
+            std::fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.actions.is_empty() {
+            return write!(f, "Nothing to do");
+        }
+
+        writeln!(f, "Planned actions ({}):", self.actions.len())?;
+        for (index, action) in self.actions.iter().enumerate() {
+            writeln!(f, "{:>4}. {}", index + 1, action)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::civitai::ModelType;
+
+/// Headers larger than this are rejected instead of being read into memory.
+const MAX_HEADER_SIZE: u64 = 100 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum SafetensorsError {
+    Io(String),
+    InvalidHeader(String),
+}
+
+impl std::fmt::Display for SafetensorsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SafetensorsError::Io(msg) => write!(f, "IO error: {}", msg),
+            SafetensorsError::InvalidHeader(msg) => write!(f, "Invalid safetensors header: {}", msg),
+        }
+    }
+}
+
+impl From<std::io::Error> for SafetensorsError {
+    fn from(e: std::io::Error) -> Self {
+        SafetensorsError::Io(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for SafetensorsError {
+    fn from(e: serde_json::Error) -> Self {
+        SafetensorsError::InvalidHeader(e.to_string())
+    }
+}
+
+impl std::error::Error for SafetensorsError {}
+
+type Result<T> = std::result::Result<T, SafetensorsError>;
+
+#[derive(Debug, Deserialize)]
+pub struct TensorInfo {
+    pub shape: Vec<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct Header {
+    pub metadata: HashMap<String, String>,
+    pub tensors: BTreeMap<String, TensorInfo>,
+}
+
+impl Header {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = std::fs::File::open(path)?;
+        Self::from_reader(file)
+    }
+
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self> {
+        let mut size_bytes = [0; 8];
+        reader.read_exact(&mut size_bytes)?;
+        let size = u64::from_le_bytes(size_bytes);
+        if size > MAX_HEADER_SIZE {
+            return Err(SafetensorsError::InvalidHeader(format!(
+                "header size of {} bytes is too large",
+                size
+            )));
+        }
+
+        let mut header_bytes = vec![0; size as usize];
+        reader.read_exact(&mut header_bytes)?;
+        let entries: HashMap<String, Value> = serde_json::from_slice(&header_bytes)?;
+
+        let mut header = Header::default();
+        for (name, value) in entries {
+            if name == "__metadata__" {
+                let metadata: HashMap<String, Value> = serde_json::from_value(value)?;
+                header.metadata = metadata
+                    .into_iter()
+                    .filter_map(|(key, value)| value.as_str().map(|v| (key, v.to_string())))
+                    .collect();
+            } else {
+                header.tensors.insert(name, serde_json::from_value(value)?);
+            }
+        }
+
+        Ok(header)
+    }
+
+    fn has_key(&self, pattern: &str) -> bool {
+        self.tensors.keys().any(|name| name.contains(pattern))
+    }
+
+    fn has_prefix(&self, prefix: &str) -> bool {
+        self.tensors.keys().any(|name| name.starts_with(prefix))
+    }
+
+    /// Width of the text conditioning fed into the UNet cross attention, which tells SD 1.x
+    /// (768), SD 2.x (1024) and SDXL (2048) apart.
+    fn cross_attention_width(&self) -> Option<u64> {
+        self.tensors.iter().find_map(|(name, tensor)| {
+            let is_key_projection = name.ends_with("attn2.to_k.weight")
+                || name.ends_with("attn2_to_k.lora_down.weight")
+                || name.ends_with("attn2.to_k.lora_down.weight");
+            match (is_key_projection, tensor.shape.as_slice()) {
+                (true, [_, width]) => Some(*width),
+                _ => None,
+            }
+        })
+    }
+
+    fn metadata_mentions(&self, needle: &str) -> bool {
+        ["modelspec.title", "modelspec.architecture", "ss_sd_model_name", "ss_base_model_version"]
+            .iter()
+            .filter_map(|key| self.metadata.get(*key))
+            .any(|value| value.to_lowercase().contains(needle))
+    }
+}
+
+/// Model type and CivitAI style base model name inferred from a header.
+#[derive(Debug, PartialEq)]
+pub struct Detection {
+    pub model_type: ModelType,
+    pub base_model: String,
+}
+
+pub fn detect_from_file<P: AsRef<Path>>(path: P) -> Result<Option<Detection>> {
+    let header = Header::from_file(path)?;
+    Ok(detect(&header))
+}
+
+pub fn detect(header: &Header) -> Option<Detection> {
+    let model_type = detect_model_type(header)?;
+    let base_model = match model_type {
+        ModelType::Upscaler => None,
+        ModelType::Vae => detect_vae_base_model(header),
+        ModelType::TextualInversion => detect_embedding_base_model(header),
+        _ => detect_base_model(header, &model_type),
+    };
+
+    Some(Detection {
+        model_type,
+        base_model: base_model.unwrap_or("Other").to_string(),
+    })
+}
+
+fn detect_model_type(header: &Header) -> Option<ModelType> {
+    let is_lora = ["lora_up", "lora_down", "lora_A", "lora_B", "hada_w1", "lokr_w1"]
+        .iter()
+        .any(|pattern| header.has_key(pattern));
+    if is_lora {
+        return Some(ModelType::Lora);
+    }
+
+    let is_controlnet = header.has_prefix("control_model.")
+        || header.has_key("input_hint_block")
+        || header.has_key("controlnet_cond_embedding")
+        || header.has_prefix("controlnet_");
+    if is_controlnet {
+        return Some(ModelType::Controlnet);
+    }
+
+    let is_checkpoint = header.has_prefix("model.diffusion_model.")
+        || header.has_key("double_blocks.")
+        || header.has_key("joint_blocks.");
+    if is_checkpoint {
+        return Some(ModelType::Checkpoint);
+    }
+
+    let is_vae = ((header.has_prefix("encoder.") || header.has_prefix("decoder.")) && header.has_key("quant_conv"))
+        || header.has_prefix("first_stage_model.");
+    if is_vae {
+        return Some(ModelType::Vae);
+    }
+
+    let is_upscaler = ["conv_first.", "RRDB_trunk.", "body.0.rdb1.", "model.0.weight"]
+        .iter()
+        .any(|pattern| header.has_key(pattern));
+    if is_upscaler {
+        return Some(ModelType::Upscaler);
+    }
+
+    let is_embedding = ["emb_params", "string_to_param", "clip_l", "clip_g"]
+        .iter()
+        .any(|pattern| header.tensors.contains_key(*pattern) || header.has_prefix(pattern))
+        && header.tensors.len() <= 4;
+    if is_embedding {
+        return Some(ModelType::TextualInversion);
+    }
+
+    None
+}
+
+fn detect_base_model(header: &Header, model_type: &ModelType) -> Option<&'static str> {
+    if header.has_key("double_blocks") {
+        // Only full checkpoints carry the guidance embedding, LoRAs are nearly always trained on
+        // the dev model
+        return Some(if *model_type != ModelType::Checkpoint || header.has_key("guidance_in") {
+            "Flux.1 D"
+        } else {
+            "Flux.1 S"
+        });
+    }
+    if header.has_key("joint_blocks") {
+        return Some("SD 3");
+    }
+
+    let is_sdxl = header.cross_attention_width() == Some(2048)
+        || header.has_prefix("conditioner.embedders.1.")
+        || header.has_prefix("lora_te2_");
+    if is_sdxl {
+        return Some(if header.metadata_mentions("pony") {
+            "Pony"
+        } else {
+            "SDXL 1.0"
+        });
+    }
+
+    match header.cross_attention_width() {
+        Some(768) => Some("SD 1.5"),
+        Some(1024) => Some("SD 2.1"),
+        _ => None,
+    }
+}
+
+fn detect_vae_base_model(header: &Header) -> Option<&'static str> {
+    // SD3 and Flux autoencoders work with 16 latent channels instead of 4
+    let latent_channels = header
+        .tensors
+        .iter()
+        .find(|(name, _)| name.ends_with("decoder.conv_in.weight"))
+        .and_then(|(_, tensor)| tensor.shape.get(1).copied());
+
+    match latent_channels {
+        Some(16) => Some("Flux.1 D"),
+        _ => None,
+    }
+}
+
+fn detect_embedding_base_model(header: &Header) -> Option<&'static str> {
+    if header.tensors.contains_key("clip_g") {
+        return Some("SDXL 1.0");
+    }
+
+    let width = header.tensors.values().find_map(|tensor| tensor.shape.last().copied());
+    match width {
+        Some(768) => Some("SD 1.5"),
+        Some(1024) => Some("SD 2.1"),
+        _ => None,
+    }
+}
@@ -0,0 +1,168 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use log::debug;
+use log::info;
+use serde::Serialize;
+
+use crate::civitai::CivitAiClient;
+use crate::civitai::Image;
+use crate::civitai::ModelInfo;
+use crate::plan::Plan;
+
+/// Link to a model version on the CivitAI website, written into the notes of the WebUI metadata.
+const MODEL_PAGE_URL: &str = "https://civitai.com/models";
+
+/// Suffixes of the files frontends expect next to a model, they travel with it when it moves.
+const SIDECAR_SUFFIXES: [&str; 4] = [".civitai.info", ".json", ".preview.png", ".png"];
+
+#[derive(Debug)]
+pub enum SidecarError {
+    Io(String),
+    SerdeJson(String),
+    CivitAi(String),
+}
+
+impl std::fmt::Display for SidecarError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SidecarError::Io(msg) => write!(f, "IO error: {}", msg),
+            SidecarError::SerdeJson(msg) => write!(f, "Serde JSON error: {}", msg),
+            SidecarError::CivitAi(msg) => write!(f, "CivitAI error: {}", msg),
+        }
+    }
+}
+
+impl From<std::io::Error> for SidecarError {
+    fn from(e: std::io::Error) -> Self {
+        SidecarError::Io(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for SidecarError {
+    fn from(e: serde_json::Error) -> Self {
+        SidecarError::SerdeJson(e.to_string())
+    }
+}
+
+impl From<crate::civitai::CivitAiError> for SidecarError {
+    fn from(e: crate::civitai::CivitAiError) -> Self {
+        SidecarError::CivitAi(e.to_string())
+    }
+}
+
+impl std::error::Error for SidecarError {}
+
+type Result<T> = std::result::Result<T, SidecarError>;
+
+/// User metadata the WebUI Lora tab reads from `<model>.json` and shows on the model card.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LoraMetadata {
+    pub description: String,
+    #[serde(rename = "sd version")]
+    pub sd_version: String,
+    #[serde(rename = "activation text")]
+    pub activation_text: String,
+    #[serde(rename = "preferred weight")]
+    pub preferred_weight: f64,
+    pub notes: String,
+}
+
+impl From<&ModelInfo> for LoraMetadata {
+    fn from(info: &ModelInfo) -> Self {
+        let trained_words: Vec<&str> = info.trained_words.iter().flatten().map(|word| word.trim()).collect();
+        Self {
+            description: info.description.clone().unwrap_or_default(),
+            sd_version: sd_version(info.base_model.as_deref().unwrap_or_default()).to_string(),
+            activation_text: trained_words.join(", "),
+            preferred_weight: 0.0,
+            notes: format!("{}/{}?modelVersionId={}", MODEL_PAGE_URL, info.model_id, info.id),
+        }
+    }
+}
+
+/// Maps a CivitAI base model onto the versions the WebUI tells apart.
+fn sd_version(base_model: &str) -> &'static str {
+    let base_model = base_model.to_lowercase();
+    if base_model.starts_with("sd 1") {
+        "SD1"
+    } else if base_model.starts_with("sd 2") {
+        "SD2"
+    } else if ["sdxl", "pony", "illustrious", "noobai"].iter().any(|name| base_model.starts_with(name)) {
+        "SDXL"
+    } else {
+        "Unknown"
+    }
+}
+
+/// `<model>.<suffix>`, with the model's extension replaced.
+pub fn sidecar_path(model: &Path, suffix: &str) -> PathBuf {
+    let stem = model.file_stem().unwrap_or_default().to_string_lossy();
+    model.with_file_name(format!("{}{}", stem, suffix))
+}
+
+/// Moves the existing sidecar files of `model` next to `new_model`, renaming them along with it.
+pub fn move_sidecars(model: &Path, new_model: &Path, plan: &mut Plan) -> Result<()> {
+    for suffix in SIDECAR_SUFFIXES {
+        let sidecar = sidecar_path(model, suffix);
+        if plan.exists(&sidecar) {
+            plan.rename(sidecar, sidecar_path(new_model, suffix))?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `<model>.civitai.info` in the format of the Civitai Helper extension and
+/// `<model>.json` for the WebUI Lora tab. The latter holds user edits, so an existing one is only
+/// replaced with `overwrite`.
+pub fn write_sidecars(model: &Path, info: &ModelInfo, overwrite: bool, plan: &mut Plan) -> Result<()> {
+    let civitai_info = sidecar_path(model, ".civitai.info");
+    plan.write_file(&civitai_info, serde_json::to_string_pretty(info)?)?;
+
+    let metadata = sidecar_path(model, ".json");
+    if plan.exists(&metadata) && !overwrite {
+        debug!("Keeping existing metadata {}", metadata.display());
+        return Ok(());
+    }
+    plan.write_file(&metadata, serde_json::to_string_pretty(&LoraMetadata::from(info))?)?;
+
+    Ok(())
+}
+
+/// The CivitAI information written next to `model` by `write_sidecars`.
+pub fn read_civitai_info(model: &Path) -> Result<ModelInfo> {
+    let contents = std::fs::read(sidecar_path(model, ".civitai.info"))?;
+    Ok(serde_json::from_slice(&contents)?)
+}
+
+/// The first still image, or the first one rated at most `max_nsfw_level`.
+pub fn select_preview(images: &[Image], max_nsfw_level: Option<u32>) -> Option<&Image> {
+    images.iter().find(|image| {
+        let is_image = image.image_type.as_deref().is_none_or(|image_type| image_type == "image");
+        let is_allowed = max_nsfw_level.is_none_or(|max_level| image.nsfw_level <= max_level);
+        is_image && is_allowed && image.url.is_some()
+    })
+}
+
+/// Downloads the preview image to `<model>.preview.png` for ComfyUI-Manager and `<model>.png`
+/// for the WebUI cards, unless both exist already.
+pub fn write_preview(model: &Path, info: &ModelInfo, client: &CivitAiClient, max_nsfw_level: Option<u32>, overwrite: bool, plan: &mut Plan) -> Result<()> {
+    let preview = sidecar_path(model, ".preview.png");
+    let thumbnail = sidecar_path(model, ".png");
+    if plan.exists(&preview) && plan.exists(&thumbnail) && !overwrite {
+        debug!("Keeping existing preview {}", preview.display());
+        return Ok(());
+    }
+
+    let Some(url) = select_preview(&info.images, max_nsfw_level).and_then(|image| image.url.as_deref()) else {
+        debug!("No suitable preview image for {}", model.display());
+        return Ok(());
+    };
+
+    info!("Downloading preview for {}", model.display());
+    let image = client.download(url)?;
+    plan.write_file(&preview, &image)?;
+    plan.write_file(&thumbnail, &image)?;
+
+    Ok(())
+}
@@ -0,0 +1,467 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use log::debug;
+use log::error;
+use log::info;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::civitai::Model;
+use crate::civitai::ModelInfo;
+use crate::hash::Fingerprint;
+use crate::hash::ModelHashes;
+use crate::link::LinkMode;
+
+/// File in the root of the general directory holding everything remembered about the library.
+pub const STORE_FILE_NAME: &str = ".model_sync.json";
+
+/// Hash cache of earlier releases, imported into the store and removed on first use.
+const LEGACY_CACHE_FILE_NAME: &str = "orphan_cache.json";
+
+const CURRENT_VERSION: u64 = 2;
+
+/// Least time between two saves by `checkpoint`, every save rewrites the whole store.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// `MIGRATIONS[n]` upgrades data of version `n` to `n + 1`. Version 0 is the path keyed hash cache
+/// that used to live in `orphan_cache.json`.
+const MIGRATIONS: [fn(Value) -> Value; 2] = [migrate_legacy_cache, migrate_sha256_only_hashes];
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(String),
+    SerdeJson(String),
+    UnsupportedVersion(u64),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Io(msg) => write!(f, "IO error: {}", msg),
+            StoreError::SerdeJson(msg) => write!(f, "Serde JSON error: {}", msg),
+            StoreError::UnsupportedVersion(version) => write!(
+                f,
+                "Store version {} was written by a newer release, expected at most {}",
+                version, CURRENT_VERSION
+            ),
+        }
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> Self {
+        StoreError::Io(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> Self {
+        StoreError::SerdeJson(e.to_string())
+    }
+}
+
+impl From<crate::hash::EldenError> for StoreError {
+    fn from(e: crate::hash::EldenError) -> Self {
+        StoreError::Io(e.to_string())
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+type Result<T> = std::result::Result<T, StoreError>;
+
+/// Hashes together with the state of the file they were calculated from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedHash {
+    #[serde(flatten)]
+    pub hashes: ModelHashes,
+    #[serde(flatten)]
+    pub fingerprint: Fingerprint,
+}
+
+/// Answer of CivitAI for a hash or model id, `info` is empty when the model is not known there.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CivitAiLookup {
+    /// Seconds since the Unix epoch.
+    pub fetched_at: u64,
+    pub info: Option<Value>,
+}
+
+impl CivitAiLookup {
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(now().saturating_sub(self.fetched_at))
+    }
+}
+
+/// How a frontend directory was last linked to the general directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkRecord {
+    pub source: PathBuf,
+    pub mode: LinkMode,
+    /// Seconds since the Unix epoch.
+    pub linked_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoreData {
+    #[serde(default)]
+    version: u64,
+    /// Keyed by path, relative to the store for files inside the general directory.
+    #[serde(default)]
+    hashes: BTreeMap<String, CachedHash>,
+    /// Keyed by SHA256.
+    #[serde(default)]
+    civitai: BTreeMap<String, CivitAiLookup>,
+    /// Keyed by CivitAI model id.
+    #[serde(default)]
+    models: BTreeMap<String, CivitAiLookup>,
+    /// Keyed by frontend directory.
+    #[serde(default)]
+    links: BTreeMap<String, LinkRecord>,
+}
+
+impl Default for StoreData {
+    fn default() -> Self {
+        Self {
+            version: CURRENT_VERSION,
+            hashes: BTreeMap::new(),
+            civitai: BTreeMap::new(),
+            models: BTreeMap::new(),
+            links: BTreeMap::new(),
+        }
+    }
+}
+
+/// Library metadata persisted in one versioned JSON file. Changes are kept in memory until
+/// `save`, which replaces the file atomically, and are saved as well when the store is dropped,
+/// so a run ending in an error or interrupted with Ctrl-C keeps them.
+#[derive(Debug, Default)]
+pub struct Store {
+    path: Option<PathBuf>,
+    data: StoreData,
+    dirty: bool,
+    dry_run: bool,
+    legacy_cache: Option<PathBuf>,
+    last_saved: Option<Instant>,
+    /// Key of the hashes of every file by device and inode, to follow renames without looking at
+    /// every entry.
+    keys_by_file: HashMap<(u64, u64), String>,
+}
+
+impl Store {
+    /// Opens the store of the general directory `root`, importing the old hash cache if there is
+    /// no store yet.
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let path = root.as_ref().join(STORE_FILE_NAME);
+        let legacy_cache = root.as_ref().join(LEGACY_CACHE_FILE_NAME);
+
+        let mut store = Store::default();
+        store.path = Some(path.clone());
+        store.last_saved = Some(Instant::now());
+        if path.is_file() {
+            store.data = read_data(&path)?;
+        } else if legacy_cache.is_file() {
+            info!("Importing hash cache {}", legacy_cache.display());
+            store.data = read_data(&legacy_cache)?;
+            store.dirty = true;
+            store.legacy_cache = Some(legacy_cache);
+        }
+        if store.data.version != CURRENT_VERSION {
+            store.data.version = CURRENT_VERSION;
+            store.dirty = true;
+        }
+        store.keys_by_file = store
+            .data
+            .hashes
+            .iter()
+            .filter_map(|(key, entry)| Some((entry.fingerprint.file_id()?, key.clone())))
+            .collect();
+
+        Ok(store)
+    }
+
+    /// A store that is never written, for runs without a general directory.
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Keeps changes in memory only, so a dry run leaves the store and the legacy cache as they
+    /// are.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Writes pending changes to a temporary file and moves it over the store, so an interrupted
+    /// run never leaves a truncated file behind.
+    pub fn save(&mut self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        if self.dry_run {
+            debug!("Not saving {} during a dry run", path.display());
+            return Ok(());
+        }
+
+        debug!("Saving {}", path.display());
+        let temporary = path.with_extension("json.tmp");
+        let file = std::fs::File::create(&temporary)?;
+        let mut writer = BufWriter::new(&file);
+        serde_json::to_writer_pretty(&mut writer, &self.data)?;
+        writer.flush()?;
+        drop(writer);
+        file.sync_all()?;
+        std::fs::rename(&temporary, path)?;
+        self.dirty = false;
+        self.last_saved = Some(Instant::now());
+
+        if let Some(legacy_cache) = self.legacy_cache.take() {
+            debug!("Removing imported hash cache {}", legacy_cache.display());
+            std::fs::remove_file(legacy_cache)?;
+        }
+
+        Ok(())
+    }
+
+    /// Saves pending changes if the last save is a while ago, for long steps that should not
+    /// lose much work when the process is killed.
+    pub fn checkpoint(&mut self) -> Result<()> {
+        if self.last_saved.is_none_or(|last_saved| last_saved.elapsed() >= CHECKPOINT_INTERVAL) {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// The SHA256 of `model` if it was calculated before and the file is unchanged since.
+    pub fn lookup_hash(&mut self, model: &Path) -> Result<Option<String>> {
+        Ok(self.lookup_hashes(model)?.map(|hashes| hashes.sha256))
+    }
+
+    /// The hashes of `model` if they were calculated before and the file is unchanged since. A
+    /// file that was renamed or moved is found again by its inode and its entry follows it.
+    pub fn lookup_hashes(&mut self, model: &Path) -> Result<Option<ModelHashes>> {
+        let key = self.key(model);
+        let fingerprint = Fingerprint::from_file(model)?;
+
+        match self.data.hashes.get(&key) {
+            Some(entry) if entry.fingerprint == fingerprint => return Ok(Some(entry.hashes.clone())),
+            Some(_) => debug!("{} changed since it was hashed", model.display()),
+            None => (),
+        }
+
+        let renamed = fingerprint
+            .file_id()
+            .and_then(|id| self.keys_by_file.get(&id))
+            .and_then(|previous_key| Some((previous_key, self.data.hashes.get(previous_key)?)))
+            .filter(|(_, entry)| entry.fingerprint.is_same_file(&fingerprint))
+            .map(|(previous_key, entry)| (previous_key.clone(), entry.hashes.clone()));
+        let Some((previous_key, hashes)) = renamed else {
+            return Ok(None);
+        };
+
+        debug!("{} was hashed as {}", model.display(), previous_key);
+        self.insert_hashes(model, &hashes)?;
+        Ok(Some(hashes))
+    }
+
+    /// Remembers the hashes of `model` with its current size, modification time and inode. The
+    /// last entry for the same file is dropped if its path no longer exists, since it was renamed,
+    /// or if it is an absolute entry imported for a file that is now stored relative to the
+    /// library.
+    pub fn insert_hashes(&mut self, model: &Path, hashes: &ModelHashes) -> Result<()> {
+        let fingerprint = Fingerprint::from_file(model)?;
+        let key = self.key(model);
+        if let Some(id) = fingerprint.file_id()
+            && let Some(previous_key) = self.keys_by_file.insert(id, key.clone())
+            && previous_key != key
+            && self
+                .data
+                .hashes
+                .get(&previous_key)
+                .is_some_and(|entry| entry.fingerprint.is_same_file(&fingerprint))
+        {
+            let previous = self.root().join(&previous_key);
+            if !previous.exists() || previous == model {
+                self.data.hashes.remove(&previous_key);
+            }
+        }
+
+        self.data.hashes.insert(
+            key,
+            CachedHash {
+                hashes: hashes.clone(),
+                fingerprint,
+            },
+        );
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Files inside the general directory the store has hashes for, whether they exist or not.
+    pub fn library_files(&self) -> Vec<PathBuf> {
+        if self.path.is_none() {
+            return vec![];
+        }
+        let root = self.root();
+        self.data
+            .hashes
+            .keys()
+            .filter(|key| Path::new(key).is_relative())
+            .map(|key| root.join(key))
+            .collect()
+    }
+
+    pub fn forget_hashes(&mut self, model: &Path) {
+        let key = self.key(model);
+        let Some(entry) = self.data.hashes.remove(&key) else {
+            return;
+        };
+        if let Some(id) = entry.fingerprint.file_id()
+            && self.keys_by_file.get(&id) == Some(&key)
+        {
+            self.keys_by_file.remove(&id);
+        }
+        self.dirty = true;
+    }
+
+    pub fn civitai_lookup(&self, hash: &str) -> Option<&CivitAiLookup> {
+        self.data.civitai.get(hash)
+    }
+
+    /// Remembers what CivitAI answered for `hash`, `None` when it does not know the model.
+    pub fn record_civitai_lookup(&mut self, hash: &str, info: Option<&ModelInfo>) -> Result<()> {
+        let info = info.map(serde_json::to_value).transpose()?;
+        self.data.civitai.insert(
+            hash.to_string(),
+            CivitAiLookup {
+                fetched_at: now(),
+                info,
+            },
+        );
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn civitai_model(&self, model_id: u64) -> Option<&CivitAiLookup> {
+        self.data.models.get(&model_id.to_string())
+    }
+
+    /// Remembers what CivitAI answered for `model_id`, `None` when it does not know the model.
+    pub fn record_civitai_model(&mut self, model_id: u64, model: Option<&Model>) -> Result<()> {
+        let info = model.map(serde_json::to_value).transpose()?;
+        self.data.models.insert(
+            model_id.to_string(),
+            CivitAiLookup {
+                fetched_at: now(),
+                info,
+            },
+        );
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn record_link(&mut self, source: &Path, target: &Path, mode: LinkMode) {
+        self.data.links.insert(
+            target.to_string_lossy().to_string(),
+            LinkRecord {
+                source: source.to_path_buf(),
+                mode,
+                linked_at: now(),
+            },
+        );
+        self.dirty = true;
+    }
+
+    pub fn remove_link(&mut self, target: &Path) {
+        if self.data.links.remove(target.to_string_lossy().as_ref()).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    fn root(&self) -> PathBuf {
+        self.path
+            .as_ref()
+            .and_then(|path| path.parent())
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    }
+
+    fn key(&self, path: &Path) -> String {
+        let root = self.root();
+        let relative = match path.strip_prefix(&root) {
+            Ok(relative) if !root.as_os_str().is_empty() => relative,
+            _ => path,
+        };
+        relative.to_string_lossy().to_string()
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        if let Err(err) = self.save() {
+            error!("Error saving the store: {}", err);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Reads a store or legacy cache and migrates it to the current version.
+fn read_data(path: &Path) -> Result<StoreData> {
+    let file = std::fs::File::open(path)?;
+    let mut value: Value = serde_json::from_reader(BufReader::new(file))?;
+
+    let version = value.get("version").and_then(Value::as_u64).unwrap_or(0);
+    if version > CURRENT_VERSION {
+        return Err(StoreError::UnsupportedVersion(version));
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        value = migration(value);
+    }
+
+    // Keeps the version the data was read with, so migrated data is written back
+    let mut data: StoreData = serde_json::from_value(value)?;
+    data.version = version;
+    Ok(data)
+}
+
+/// Keeps the entries of the old hash cache that carry a fingerprint, plain path to hash entries
+/// from before that cannot be trusted and are hashed again.
+fn migrate_legacy_cache(value: Value) -> Value {
+    let hashes: serde_json::Map<String, Value> = match value {
+        Value::Object(entries) => entries.into_iter().filter(|(_, entry)| entry.is_object()).collect(),
+        _ => serde_json::Map::new(),
+    };
+    serde_json::json!({ "hashes": hashes })
+}
+
+/// Version 1 only kept the SHA256, under the name `hash`.
+fn migrate_sha256_only_hashes(mut value: Value) -> Value {
+    if let Some(hashes) = value.get_mut("hashes").and_then(Value::as_object_mut) {
+        for entry in hashes.values_mut().filter_map(Value::as_object_mut) {
+            if let Some(hash) = entry.remove("hash") {
+                entry.insert("sha256".to_string(), hash);
+            }
+        }
+    }
+    value
+}
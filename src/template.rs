@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Placeholders a sort destination may use.
+pub const PLACEHOLDERS: [&str; 11] = [
+    "type",
+    "model_type",
+    "base_model",
+    "base_model_type",
+    "model_id",
+    "model_name",
+    "version_id",
+    "version_name",
+    "creator",
+    "nsfw",
+    "file_name",
+];
+
+/// Stands in for values that are not known, like the model name of a model CivitAI does not know.
+pub const UNKNOWN: &str = "unknown";
+
+/// Characters that are not allowed in file names on at least one common filesystem.
+const INVALID_CHARACTERS: [char; 9] = ['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// File names Windows reserves for devices, with any extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[derive(Debug, PartialEq)]
+pub enum TemplateError {
+    UnknownPlaceholder(String),
+    UnknownModifier(String),
+    Unclosed(String),
+    InvalidPath(String),
+    MissingFileName(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::UnknownPlaceholder(name) => write!(
+                f,
+                "Unknown placeholder {{{}}}, expected one of {}",
+                name,
+                PLACEHOLDERS.join(", ")
+            ),
+            TemplateError::UnknownModifier(modifier) => {
+                write!(f, "Unknown modifier :{}, only :lower is supported", modifier)
+            }
+            TemplateError::Unclosed(template) => write!(f, "Unclosed placeholder in {}", template),
+            TemplateError::InvalidPath(template) => {
+                write!(f, "{} does not describe a path inside the general directory", template)
+            }
+            TemplateError::MissingFileName(template) => {
+                write!(f, "{} must end with a file name containing {{file_name}}", template)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+type Result<T> = std::result::Result<T, TemplateError>;
+
+/// A part of a parsed template.
+enum Part<'a> {
+    Text(&'a str),
+    Placeholder { name: &'a str, lower: bool },
+}
+
+/// The placeholders used in `template`, checking that they all exist.
+pub fn placeholders(template: &str) -> Result<Vec<&str>> {
+    Ok(parse(template)?
+        .into_iter()
+        .filter_map(|part| match part {
+            Part::Placeholder { name, .. } => Some(name),
+            Part::Text(_) => None,
+        })
+        .collect())
+}
+
+/// Checks that `template` only uses known placeholders and that its last component holds
+/// `{file_name}`, which carries the extension. Otherwise every model would be moved to the same
+/// extensionless file.
+pub fn check_destination(template: &str) -> Result<()> {
+    placeholders(template)?;
+    let file_name = template.rsplit('/').next().unwrap_or_default();
+    let has_file_name = parse(file_name)?
+        .iter()
+        .any(|part| matches!(part, Part::Placeholder { name: "file_name", .. }));
+    if !has_file_name {
+        return Err(TemplateError::MissingFileName(template.to_string()));
+    }
+    Ok(())
+}
+
+/// Renders `template` into a relative path. `/` separates directories, values are sanitized so
+/// they always form a single path component, except for `type` which is a directory of the general
+/// directory and may span several. Placeholders without a value render as `unknown`, `{name:lower}`
+/// lowercases the value.
+pub fn render(template: &str, values: &BTreeMap<&str, String>) -> Result<PathBuf> {
+    let mut rendered = String::new();
+    for part in parse(template)? {
+        match part {
+            Part::Text(text) => rendered.push_str(text),
+            Part::Placeholder { name, lower } => {
+                let value = match values.get(name) {
+                    Some(value) if name == "type" => value.replace('\\', "/"),
+                    Some(value) => sanitize(value),
+                    None => UNKNOWN.to_string(),
+                };
+                rendered.push_str(&if lower { value.to_lowercase() } else { value });
+            }
+        }
+    }
+
+    let mut path = PathBuf::new();
+    for component in rendered.split('/').filter(|component| !component.is_empty() && *component != ".") {
+        if component == ".." {
+            return Err(TemplateError::InvalidPath(template.to_string()));
+        }
+        path.push(component);
+    }
+    if path.as_os_str().is_empty() || path.is_absolute() {
+        return Err(TemplateError::InvalidPath(template.to_string()));
+    }
+    Ok(path)
+}
+
+/// Turns `value` into a valid file name: characters that are not allowed are replaced by `_`, and
+/// leading and trailing dots and spaces are removed, as Windows does not keep them.
+pub fn sanitize(value: &str) -> String {
+    let replaced: String = value
+        .chars()
+        .map(|c| if c.is_control() || INVALID_CHARACTERS.contains(&c) { '_' } else { c })
+        .collect();
+    let trimmed = replaced.trim_matches(|c: char| c == '.' || c.is_whitespace());
+    if trimmed.is_empty() {
+        return UNKNOWN.to_string();
+    }
+
+    let stem = trimmed.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES.iter().any(|name| name.eq_ignore_ascii_case(stem)) {
+        return format!("_{}", trimmed);
+    }
+    trimmed.to_string()
+}
+
+fn parse(template: &str) -> Result<Vec<Part<'_>>> {
+    let mut parts = vec![];
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            parts.push(Part::Text(&rest[..start]));
+        }
+        let Some(length) = rest[start..].find('}') else {
+            return Err(TemplateError::Unclosed(template.to_string()));
+        };
+        let placeholder = &rest[start + 1..start + length];
+        let (name, lower) = match placeholder.split_once(':') {
+            None => (placeholder, false),
+            Some((name, "lower")) => (name, true),
+            Some((_, modifier)) => return Err(TemplateError::UnknownModifier(modifier.to_string())),
+        };
+        if !PLACEHOLDERS.contains(&name) {
+            return Err(TemplateError::UnknownPlaceholder(name.to_string()));
+        }
+        parts.push(Part::Placeholder { name, lower });
+        rest = &rest[start + length + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Text(rest));
+    }
+    Ok(parts)
+}
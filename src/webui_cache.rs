@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::BufReader;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use log::debug;
+use log::info;
+use log::warn;
+use serde::Deserialize;
+
+use crate::api::is_model_file;
+use crate::configuration::FolderStructure;
+use crate::configuration::WebUIConfig;
+use crate::hash::ModelHashes;
+use crate::link;
+use crate::store::Store;
+
+/// Hash cache the WebUI keeps in its data directory.
+pub const CACHE_FILE_NAME: &str = "cache.json";
+
+/// The WebUI stores modification times as float seconds, which do not round trip exactly.
+const MTIME_TOLERANCE_SECS: f64 = 0.001;
+
+#[derive(Debug)]
+pub enum WebUiCacheError {
+    Io(String),
+    SerdeJson(String),
+    Store(String),
+}
+
+impl fmt::Display for WebUiCacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebUiCacheError::Io(msg) => write!(f, "IO error: {}", msg),
+            WebUiCacheError::SerdeJson(msg) => write!(f, "Serde JSON error: {}", msg),
+            WebUiCacheError::Store(msg) => write!(f, "Store error: {}", msg),
+        }
+    }
+}
+
+impl From<std::io::Error> for WebUiCacheError {
+    fn from(e: std::io::Error) -> Self {
+        WebUiCacheError::Io(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for WebUiCacheError {
+    fn from(e: serde_json::Error) -> Self {
+        WebUiCacheError::SerdeJson(e.to_string())
+    }
+}
+
+impl From<crate::store::StoreError> for WebUiCacheError {
+    fn from(e: crate::store::StoreError) -> Self {
+        WebUiCacheError::Store(e.to_string())
+    }
+}
+
+impl std::error::Error for WebUiCacheError {}
+
+type Result<T> = std::result::Result<T, WebUiCacheError>;
+
+#[derive(Debug, Deserialize)]
+struct CacheFile {
+    /// Keyed by `<kind>/<name>`, SHA256 of the whole file.
+    #[serde(default)]
+    hashes: BTreeMap<String, CacheEntry>,
+    /// Keyed like `hashes`, but the hash only covers the tensor data of safetensors files, so it
+    /// cannot stand in for the SHA256.
+    #[serde(default, rename = "hashes-addnet")]
+    hashes_addnet: BTreeMap<String, CacheEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CacheEntry {
+    /// Seconds since the Unix epoch.
+    mtime: f64,
+    sha256: String,
+}
+
+/// What became of the entries of a WebUI hash cache.
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportSummary {
+    pub imported: usize,
+    /// Files the store already had a hash for.
+    pub known: usize,
+    /// Files changed since the WebUI hashed them.
+    pub stale: usize,
+    /// Entries of files that are gone or of kinds we do not link.
+    pub missing: usize,
+    /// Entries only naming a file, when files of that name are in several folders.
+    pub ambiguous: usize,
+    /// Hashes of only the tensor data, from `hashes-addnet`.
+    pub unsupported: usize,
+}
+
+impl fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Imported {} hashes, {} already known, {} outdated, {} not found, {} ambiguous, {} not a full SHA256",
+            self.imported, self.known, self.stale, self.missing, self.ambiguous, self.unsupported
+        )
+    }
+}
+
+/// Seeds `store` with the SHA256 hashes the WebUI at `webui` calculated before, for every file
+/// that has not changed since. Entries are stored under the path the WebUI file resolves to, so
+/// models linked from the general directory are found right away and the others once they are
+/// moved there.
+pub fn import_hashes(webui: &WebUIConfig, store: &mut Store) -> Result<ImportSummary> {
+    let cache_path = webui.path.join(CACHE_FILE_NAME);
+    info!("Importing hashes from {}", cache_path.display());
+    let file = std::fs::File::open(&cache_path)?;
+    let cache: CacheFile = serde_json::from_reader(BufReader::new(file))?;
+    let structure = FolderStructure::try_from(webui.clone())?;
+
+    let mut summary = ImportSummary {
+        unsupported: cache.hashes_addnet.len(),
+        ..Default::default()
+    };
+    let mut listings = BTreeMap::new();
+    for (key, entry) in cache.hashes {
+        let model = match find_model(&structure, &key, &mut listings) {
+            ModelMatch::Found(model) => model,
+            ModelMatch::Missing => {
+                debug!("No model found for {}", key);
+                summary.missing += 1;
+                continue;
+            }
+            ModelMatch::Ambiguous => {
+                debug!("Several models could be {}", key);
+                summary.ambiguous += 1;
+                continue;
+            }
+        };
+
+        let sha256 = entry.sha256.to_uppercase();
+        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            warn!("Ignoring invalid hash {} for {}", entry.sha256, key);
+            summary.missing += 1;
+            continue;
+        }
+        if !is_unchanged(&model, entry.mtime)? {
+            debug!("{} changed since the WebUI hashed it", model.display());
+            summary.stale += 1;
+            continue;
+        }
+
+        let model = model.canonicalize()?;
+        if store.lookup_hash(&model)?.is_some() {
+            summary.known += 1;
+            continue;
+        }
+        debug!("Imported hash of {}", model.display());
+        store.insert_hashes(&model, &ModelHashes::from_sha256(sha256))?;
+        summary.imported += 1;
+    }
+
+    Ok(summary)
+}
+
+/// What a cache key resolved to.
+enum ModelMatch {
+    Found(PathBuf),
+    Missing,
+    Ambiguous,
+}
+
+/// The file behind a cache key. Checkpoints are keyed by their path relative to the checkpoint
+/// directory, other kinds by that path without extension or only by name. A bare name is only
+/// trusted if a single file has it.
+fn find_model(structure: &FolderStructure, key: &str, listings: &mut BTreeMap<PathBuf, Vec<PathBuf>>) -> ModelMatch {
+    let Some((kind, name)) = key.split_once('/') else {
+        return ModelMatch::Missing;
+    };
+    let category = match kind {
+        "checkpoint" => "checkpoints",
+        "lora" => "loras",
+        "textual_inversion" => "embeddings",
+        "vae" => "vae",
+        _ => return ModelMatch::Missing,
+    };
+    let Some(dir) = structure.categories.get(category) else {
+        return ModelMatch::Missing;
+    };
+
+    let direct = dir.join(name);
+    if direct.is_file() {
+        return ModelMatch::Found(direct);
+    }
+
+    // Frontend directories are often links into the general directory
+    let Ok(dir) = dir.canonicalize() else {
+        return ModelMatch::Missing;
+    };
+    let files = listings.entry(dir.clone()).or_insert_with(|| {
+        link::collect_files(&dir)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|file| is_model_file(file))
+            .collect()
+    });
+    let by_path = files.iter().find(|file| {
+        let relative = file.strip_prefix(&dir).unwrap_or(file);
+        relative.with_extension("") == Path::new(name)
+    });
+    if let Some(file) = by_path {
+        return ModelMatch::Found(file.clone());
+    }
+
+    let mut by_name = files.iter().filter(|file| file.file_stem().is_some_and(|stem| stem == name));
+    match (by_name.next(), by_name.next()) {
+        (Some(file), None) => ModelMatch::Found(file.clone()),
+        (None, _) => ModelMatch::Missing,
+        (Some(_), Some(_)) => ModelMatch::Ambiguous,
+    }
+}
+
+fn is_unchanged(model: &Path, cached_mtime: f64) -> Result<bool> {
+    let mtime = std::fs::metadata(model)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs_f64())
+        .unwrap_or_default();
+    Ok((mtime - cached_mtime).abs() < MTIME_TOLERANCE_SECS)
+}
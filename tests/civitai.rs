@@ -0,0 +1,726 @@
+mod common;
+
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use common::model_sync;
+use common::Response;
+use common::StubServer;
+
+const API_KEY: &str = "test-secret-token";
+
+/// Serves fixtures only to requests carrying the test API key, like gated models on CivitAI.
+fn authenticated_server() -> StubServer {
+    let fixtures = common::fixtures_dir().join("civitai");
+    StubServer::start(move |request| {
+        if request.header("Authorization") != Some(&format!("Bearer {}", API_KEY)) {
+            return Response::new(401, r#"{"error":"Unauthorized"}"#);
+        }
+        common::serve_fixture(&fixtures, request)
+    })
+}
+
+/// Writes a config with fast retries and no rate limit, plus any extra `[civitai]` settings.
+fn retry_config(dir: &std::path::Path, extra: &str) -> std::path::PathBuf {
+    let config = dir.join("config.toml");
+    let contents = format!("[civitai]\nretry_backoff_ms = 10\nrequests_per_second = 0\n{}", extra);
+    std::fs::write(&config, contents).unwrap();
+    config
+}
+
+#[test]
+fn sort_moves_models_found_in_fixtures() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    std::fs::write(general.path().join("lora.safetensors"), common::LORA_FIXTURE).unwrap();
+    std::fs::write(general.path().join("model.safetensors"), common::CHECKPOINT_FIXTURE).unwrap();
+    std::fs::write(general.path().join("unknown.ckpt"), b"not on civitai").unwrap();
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "sort"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    assert!(general.path().join("loras/sdxl 1.0/lora.safetensors").is_file());
+    assert!(general.path().join("checkpoints/sd 1.5/model.safetensors").is_file());
+    assert!(general.path().join("unknown.ckpt").is_file());
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 3);
+    assert!(requests.iter().all(|r| r.method == "GET" && r.path.starts_with("/model-versions/by-hash/")));
+}
+
+#[test]
+fn info_prints_fixture_model() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    let model = general.path().join("lora.safetensors");
+    std::fs::write(&model, common::LORA_FIXTURE).unwrap();
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "info", model.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Detail Tweaker XL"));
+    assert!(stdout.contains(common::LORA_FIXTURE_HASH));
+}
+
+#[test]
+fn api_url_from_config_file() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    std::fs::write(general.path().join("lora.safetensors"), common::LORA_FIXTURE).unwrap();
+    let config = general.path().join("config.toml");
+    std::fs::write(&config, format!("[civitai]\napi_url = \"{}/\"\n", server.url)).unwrap();
+
+    let output = common::model_sync_command(&server)
+        .args([general.path().to_str().unwrap(), "sort", "-t", config.to_str().unwrap()])
+        .env_remove("CIVITAI_API_URL")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    assert!(general.path().join("loras/sdxl 1.0/lora.safetensors").is_file());
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[test]
+fn api_key_is_sent_and_redacted() {
+    let server = authenticated_server();
+    let general = tempfile::tempdir().unwrap();
+    std::fs::write(general.path().join("lora.safetensors"), common::LORA_FIXTURE).unwrap();
+    let config = general.path().join("config.toml");
+    std::fs::write(&config, format!("[civitai]\napi_key = \"{}\"\n", API_KEY)).unwrap();
+
+    let output = model_sync(
+        &server,
+        &[general.path().to_str().unwrap(), "sort", "-v", "4", "-t", config.to_str().unwrap()],
+    );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(general.path().join("loras/sdxl 1.0/lora.safetensors").is_file());
+
+    let logs = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(logs.contains("<redacted>"));
+    assert!(!logs.contains(API_KEY));
+}
+
+#[test]
+fn api_key_from_env_and_file() {
+    let server = authenticated_server();
+    let general = tempfile::tempdir().unwrap();
+    std::fs::write(general.path().join("a.safetensors"), common::LORA_FIXTURE).unwrap();
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "sort"]);
+    assert!(output.status.success());
+    assert!(general.path().join("a.safetensors").is_file(), "sorted without a key");
+
+    let output = common::model_sync_command(&server)
+        .args([general.path().to_str().unwrap(), "sort"])
+        .env("CIVITAI_API_KEY", API_KEY)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(general.path().join("loras/sdxl 1.0/a.safetensors").is_file());
+
+    std::fs::write(general.path().join("b.safetensors"), common::LORA_FIXTURE).unwrap();
+    let key_file = general.path().join("civitai_api_key");
+    std::fs::write(&key_file, format!("{}\n", API_KEY)).unwrap();
+    let config = general.path().join("config.toml");
+    std::fs::write(&config, format!("[civitai]\napi_key_file = {:?}\n", key_file)).unwrap();
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "sort", "-t", config.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(general.path().join("loras/sdxl 1.0/b.safetensors").is_file());
+}
+
+#[test]
+fn retries_rate_limited_and_server_errors() {
+    let fixtures = common::fixtures_dir().join("civitai");
+    let attempts = AtomicUsize::new(0);
+    let server = StubServer::start(move |request| match attempts.fetch_add(1, Ordering::SeqCst) {
+        0 => Response::new(429, r#"{"error":"Too many requests"}"#).with_header("Retry-After", "0"),
+        1 => Response::new(503, r#"{"error":"Service unavailable"}"#),
+        _ => common::serve_fixture(&fixtures, request),
+    });
+    let general = tempfile::tempdir().unwrap();
+    let model = general.path().join("lora.safetensors");
+    std::fs::write(&model, common::LORA_FIXTURE).unwrap();
+    let config = retry_config(general.path(), "");
+
+    let output = model_sync(
+        &server,
+        &[general.path().to_str().unwrap(), "info", model.to_str().unwrap(), "-t", config.to_str().unwrap()],
+    );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Detail Tweaker XL"));
+    assert_eq!(server.requests().len(), 3);
+}
+
+#[test]
+fn unauthorized_is_not_retried() {
+    let server = authenticated_server();
+    let general = tempfile::tempdir().unwrap();
+    let model = general.path().join("lora.safetensors");
+    std::fs::write(&model, common::LORA_FIXTURE).unwrap();
+    let config = retry_config(general.path(), "");
+
+    let output = model_sync(
+        &server,
+        &[general.path().to_str().unwrap(), "info", model.to_str().unwrap(), "-t", config.to_str().unwrap()],
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unauthorized"));
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[test]
+fn sort_defers_models_while_rate_limited() {
+    let server = StubServer::start(|_| Response::new(429, r#"{"error":"Too many requests"}"#).with_header("Retry-After", "0"));
+    let general = tempfile::tempdir().unwrap();
+    std::fs::write(general.path().join("lora.safetensors"), common::LORA_FIXTURE).unwrap();
+    std::fs::write(general.path().join("model.safetensors"), common::CHECKPOINT_FIXTURE).unwrap();
+    let config = retry_config(general.path(), "max_retries = 1\n");
+
+    let output = model_sync(
+        &server,
+        &[general.path().to_str().unwrap(), "sort", "-v", "1", "-t", config.to_str().unwrap()],
+    );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("2 models were not sorted"));
+
+    assert!(general.path().join("lora.safetensors").is_file());
+    assert!(general.path().join("model.safetensors").is_file());
+    assert_eq!(server.requests().len(), 2, "kept asking after being rate limited");
+}
+
+#[test]
+fn requests_are_rate_limited() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    for name in ["a.ckpt", "b.ckpt", "c.ckpt"] {
+        std::fs::write(general.path().join(name), name).unwrap();
+    }
+    let config = general.path().join("config.toml");
+    std::fs::write(&config, "[civitai]\nrequests_per_second = 4\n").unwrap();
+
+    let started = Instant::now();
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "sort", "-t", config.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(server.requests().len(), 3);
+    assert!(started.elapsed() >= Duration::from_millis(500));
+}
+
+#[test]
+fn sort_writes_sidecars_and_moves_existing_ones() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    std::fs::write(general.path().join("lora.safetensors"), common::LORA_FIXTURE).unwrap();
+    std::fs::write(general.path().join("lora.preview.png"), b"preview").unwrap();
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "sort"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let sorted = general.path().join("loras/sdxl 1.0");
+    assert!(sorted.join("lora.preview.png").is_file());
+    assert!(!general.path().join("lora.preview.png").exists());
+
+    let info: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(sorted.join("lora.civitai.info")).unwrap()).unwrap();
+    assert_eq!(info["modelId"], 122359);
+    assert_eq!(info["model"]["type"], "LORA");
+
+    let metadata: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(sorted.join("lora.json")).unwrap()).unwrap();
+    assert_eq!(metadata["activation text"], "detailed");
+    assert_eq!(metadata["sd version"], "SDXL");
+}
+
+#[test]
+fn dry_run_sync_leaves_library_untouched() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    let webui = tempfile::tempdir().unwrap();
+    std::fs::write(general.path().join("lora.safetensors"), common::LORA_FIXTURE).unwrap();
+
+    let output = model_sync(
+        &server,
+        &[general.path().to_str().unwrap(), "sync", "--dry-run", "-w", webui.path().to_str().unwrap()],
+    );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    assert!(general.path().join("lora.safetensors").is_file());
+    assert!(!general.path().join(".model_sync.json").exists());
+    assert!(!general.path().join("loras").exists());
+    assert!(!webui.path().join("models").exists());
+
+    // The category the sort creates is linked like in a real run
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("loras/sdxl 1.0/lora.civitai.info"));
+    assert!(stdout.contains(&format!("symlink   {}", webui.path().join("models/Lora").display())));
+}
+
+#[test]
+fn metadata_command_keeps_user_edits() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    let loras = general.path().join("loras");
+    std::fs::create_dir_all(&loras).unwrap();
+    std::fs::write(loras.join("lora.safetensors"), common::LORA_FIXTURE).unwrap();
+    std::fs::write(loras.join("lora.json"), r#"{"activation text": "edited"}"#).unwrap();
+    std::fs::write(loras.join("unknown.safetensors"), b"not on civitai").unwrap();
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "metadata"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(loras.join("lora.civitai.info").is_file());
+    assert!(!loras.join("unknown.civitai.info").exists());
+    assert!(std::fs::read_to_string(loras.join("lora.json")).unwrap().contains("edited"));
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "metadata", "--overwrite"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(std::fs::read_to_string(loras.join("lora.json")).unwrap().contains("detailed"));
+}
+
+/// Serves the LoRA fixture with an NSFW image ahead of a safe one, both hosted on the stub.
+fn preview_server() -> StubServer {
+    let fixture = common::fixtures_dir()
+        .join("civitai/model-versions/by-hash")
+        .join(format!("{}.json", common::LORA_FIXTURE_HASH));
+    StubServer::start(move |request| {
+        let host = format!("http://{}", request.header("Host").unwrap_or_default());
+        match request.path.as_str() {
+            "/images/nsfw.jpeg" => Response::new(200, "nsfw image"),
+            "/images/sfw.jpeg" => Response::new(200, "sfw image"),
+            path if path.ends_with(common::LORA_FIXTURE_HASH) => {
+                let mut info: serde_json::Value = serde_json::from_slice(&std::fs::read(&fixture).unwrap()).unwrap();
+                let mut nsfw = info["images"][0].clone();
+                nsfw["url"] = format!("{}/images/nsfw.jpeg", host).into();
+                nsfw["nsfwLevel"] = 8.into();
+                let mut sfw = info["images"][0].clone();
+                sfw["url"] = format!("{}/images/sfw.jpeg", host).into();
+                sfw["nsfwLevel"] = 1.into();
+                info["images"] = serde_json::json!([nsfw, sfw]);
+                Response::new(200, info.to_string())
+            }
+            _ => Response::new(404, r#"{"error":"Model not found"}"#),
+        }
+    })
+}
+
+#[test]
+fn sort_downloads_previews_under_nsfw_cap() {
+    let server = preview_server();
+    let general = tempfile::tempdir().unwrap();
+    std::fs::write(general.path().join("lora.safetensors"), common::LORA_FIXTURE).unwrap();
+    let config = general.path().join("config.toml");
+    std::fs::write(&config, "[sort]\npreviews = true\nmax_nsfw_level = 1\n").unwrap();
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "sort", "-t", config.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let sorted = general.path().join("loras/sdxl 1.0");
+    assert_eq!(std::fs::read(sorted.join("lora.preview.png")).unwrap(), b"sfw image");
+    assert_eq!(std::fs::read(sorted.join("lora.png")).unwrap(), b"sfw image");
+    assert!(!server.requests().iter().any(|r| r.path == "/images/nsfw.jpeg"));
+}
+
+#[test]
+fn metadata_command_downloads_first_preview() {
+    let server = preview_server();
+    let general = tempfile::tempdir().unwrap();
+    let model = general.path().join("lora.safetensors");
+    std::fs::write(&model, common::LORA_FIXTURE).unwrap();
+
+    let args = [general.path().to_str().unwrap(), "metadata", model.to_str().unwrap(), "--previews"];
+    let output = model_sync(&server, &args);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(std::fs::read(general.path().join("lora.preview.png")).unwrap(), b"nsfw image");
+
+    let output = model_sync(&server, &args);
+    assert!(output.status.success());
+    let downloads = server.requests().iter().filter(|r| r.path.starts_with("/images/")).count();
+    assert_eq!(downloads, 1, "downloaded an existing preview again");
+}
+
+#[test]
+fn previews_from_other_hosts_get_no_api_key() {
+    let images = StubServer::start(|_| Response::new(200, "image"));
+    let image_url = format!("{}/images/preview.jpeg", images.url);
+    let fixture = common::fixtures_dir()
+        .join("civitai/model-versions/by-hash")
+        .join(format!("{}.json", common::LORA_FIXTURE_HASH));
+    let server = StubServer::start(move |request| {
+        if request.header("Authorization") != Some(&format!("Bearer {}", API_KEY)) {
+            return Response::new(401, r#"{"error":"Unauthorized"}"#);
+        }
+        if !request.path.ends_with(common::LORA_FIXTURE_HASH) {
+            return Response::new(404, r#"{"error":"Model not found"}"#);
+        }
+        let mut info: serde_json::Value = serde_json::from_slice(&std::fs::read(&fixture).unwrap()).unwrap();
+        info["images"][0]["url"] = image_url.clone().into();
+        Response::new(200, info.to_string())
+    });
+    let general = tempfile::tempdir().unwrap();
+    let model = general.path().join("lora.safetensors");
+    std::fs::write(&model, common::LORA_FIXTURE).unwrap();
+    let config = general.path().join("config.toml");
+    std::fs::write(&config, format!("[civitai]\napi_key = \"{}\"\n", API_KEY)).unwrap();
+
+    let output = model_sync(
+        &server,
+        &[
+            general.path().to_str().unwrap(),
+            "metadata",
+            model.to_str().unwrap(),
+            "--previews",
+            "-t",
+            config.to_str().unwrap(),
+        ],
+    );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(std::fs::read(general.path().join("lora.preview.png")).unwrap(), b"image");
+
+    let downloads = images.requests();
+    assert_eq!(downloads.len(), 1);
+    assert_eq!(downloads[0].header("Authorization"), None);
+}
+
+#[test]
+fn lookups_are_cached_until_refresh() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    let loras = general.path().join("loras");
+    std::fs::create_dir_all(&loras).unwrap();
+    let model = loras.join("lora.safetensors");
+    std::fs::write(&model, common::LORA_FIXTURE).unwrap();
+    std::fs::write(general.path().join("unknown.ckpt"), b"not on civitai").unwrap();
+    let root = general.path().to_str().unwrap();
+
+    for _ in 0..2 {
+        let output = model_sync(&server, &[root, "info", model.to_str().unwrap()]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert!(String::from_utf8_lossy(&output.stdout).contains("Detail Tweaker XL"));
+        assert!(model_sync(&server, &[root, "sort"]).status.success());
+    }
+    assert_eq!(server.requests().len(), 2, "asked again for cached answers");
+    assert!(general.path().join("unknown.ckpt").is_file());
+
+    assert!(model_sync(&server, &[root, "info", model.to_str().unwrap(), "--refresh"]).status.success());
+    assert!(model_sync(&server, &[root, "sort", "--refresh"]).status.success());
+    assert_eq!(server.requests().len(), 4);
+}
+
+#[test]
+fn offline_uses_cache_of_any_age() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    let model = general.path().join("lora.safetensors");
+    std::fs::write(&model, common::LORA_FIXTURE).unwrap();
+    let root = general.path().to_str().unwrap();
+    let config = general.path().join("config.toml");
+    std::fs::write(&config, "[civitai]\ncache_ttl_hours = 0\nnot_found_ttl_hours = 0\n").unwrap();
+    let config = config.to_str().unwrap();
+
+    assert!(model_sync(&server, &[root, "info", model.to_str().unwrap(), "-t", config]).status.success());
+    assert!(model_sync(&server, &[root, "info", model.to_str().unwrap(), "-t", config]).status.success());
+    assert_eq!(server.requests().len(), 2, "expired answers were reused");
+
+    let output = model_sync(&server, &[root, "info", model.to_str().unwrap(), "-t", config, "--offline"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Detail Tweaker XL"));
+
+    std::fs::write(general.path().join("other.safetensors"), common::CHECKPOINT_FIXTURE).unwrap();
+    let output = model_sync(&server, &[root, "sort", "-t", config, "--offline"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(general.path().join("loras/sdxl 1.0/lora.safetensors").is_file());
+    assert!(general.path().join("other.safetensors").is_file());
+    assert_eq!(server.requests().len(), 2, "contacted CivitAI while offline");
+}
+
+#[test]
+fn hash_command_hashes_in_parallel() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    for name in ["a.safetensors", "b.safetensors", "c.safetensors"] {
+        std::fs::write(general.path().join(name), common::LORA_FIXTURE).unwrap();
+    }
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "hash", "--jobs", "2"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.matches(common::LORA_FIXTURE_HASH).count(), 3);
+    assert!(server.requests().is_empty());
+}
+
+#[test]
+fn hash_command_prints_all_hashes() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    let model = general.path().join("lora.safetensors");
+    std::fs::write(&model, common::LORA_FIXTURE).unwrap();
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "hash", "--all", model.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for name in ["AutoV1", "AutoV2", "SHA256", "CRC32", "BLAKE3"] {
+        assert!(stdout.contains(name), "{} missing from {}", name, stdout);
+    }
+    assert!(stdout.contains(&common::LORA_FIXTURE_HASH[..10]));
+
+    // The other hashes are cached along with the SHA256
+    let store = std::fs::read_to_string(general.path().join(".model_sync.json")).unwrap();
+    assert!(store.contains("\"crc32\""));
+}
+
+#[test]
+fn import_hashes_from_webui_cache() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    let webui = tempfile::tempdir().unwrap();
+    let checkpoint = webui.path().join("models/Stable-diffusion/sdxl/model.safetensors");
+    let lora = webui.path().join("models/Lora/style/old.pt");
+    let edited = webui.path().join("models/Lora/edited.pt");
+    let twin = webui.path().join("models/Lora/a/twin.pt");
+    let other_twin = webui.path().join("models/Lora/b/twin.pt");
+    for model in [&checkpoint, &lora, &edited, &twin, &other_twin] {
+        std::fs::create_dir_all(model.parent().unwrap()).unwrap();
+        std::fs::write(model, model.to_string_lossy().as_bytes()).unwrap();
+    }
+    let mtime = |model: &std::path::Path| {
+        let modified = std::fs::metadata(model).unwrap().modified().unwrap();
+        modified.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64()
+    };
+
+    let cache = serde_json::json!({
+        "hashes": {
+            "checkpoint/sdxl/model.safetensors": {"mtime": mtime(&checkpoint), "sha256": "ab".repeat(32)},
+            "lora/old": {"mtime": mtime(&lora), "sha256": "cd".repeat(32)},
+            "lora/edited": {"mtime": mtime(&edited) - 60.0, "sha256": "ef".repeat(32)},
+            "lora/deleted": {"mtime": 0.0, "sha256": "12".repeat(32)},
+            "lora/a/twin": {"mtime": mtime(&twin), "sha256": "56".repeat(32)},
+            "lora/twin": {"mtime": mtime(&other_twin), "sha256": "78".repeat(32)},
+        },
+        "hashes-addnet": {
+            "lora/style": {"mtime": 0.0, "sha256": "34".repeat(32)},
+        },
+    });
+    std::fs::write(webui.path().join("cache.json"), cache.to_string()).unwrap();
+
+    let general_arg = general.path().to_str().unwrap();
+    let webui_arg = webui.path().to_str().unwrap();
+    let output = model_sync(&server, &[general_arg, "--webui", webui_arg, "import-hashes"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Imported 3 hashes"), "{}", stdout);
+    assert!(stdout.contains("1 outdated, 1 not found, 1 ambiguous, 1 not a full SHA256"), "{}", stdout);
+
+    let output = model_sync(&server, &[general_arg, "hash", checkpoint.to_str().unwrap(), lora.to_str().unwrap()]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&"AB".repeat(32)), "{}", stdout);
+    assert!(stdout.contains(&"CD".repeat(32)), "{}", stdout);
+}
+
+#[test]
+fn verify_reports_corrupted_truncated_and_missing_files() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    std::fs::write(general.path().join("lora.safetensors"), common::LORA_FIXTURE).unwrap();
+    std::fs::write(general.path().join("model.safetensors"), common::CHECKPOINT_FIXTURE).unwrap();
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "sort"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let lora = general.path().join("loras/sdxl 1.0/lora.safetensors");
+    let checkpoint = general.path().join("checkpoints/sd 1.5/model.safetensors");
+    let extra = general.path().join("loras/extra.safetensors");
+    std::fs::write(&extra, b"not on civitai").unwrap();
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "verify"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Verified 3 models, no problems found"));
+
+    // Flipped bits keep size and modification time
+    let modified = std::fs::metadata(&lora).unwrap().modified().unwrap();
+    let mut corrupted = common::LORA_FIXTURE.to_vec();
+    corrupted[0] ^= 0xFF;
+    std::fs::write(&lora, corrupted).unwrap();
+    std::fs::File::options().write(true).open(&lora).unwrap().set_modified(modified).unwrap();
+    std::fs::write(&checkpoint, &common::CHECKPOINT_FIXTURE[..20]).unwrap();
+    std::fs::remove_file(&extra).unwrap();
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "verify", "--prune"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("{}: corrupted", lora.display())), "{}", stdout);
+    assert!(stdout.contains(&format!("{}: truncated, 20 bytes", checkpoint.display())), "{}", stdout);
+    assert!(stdout.contains(&format!("{}: missing", extra.display())), "{}", stdout);
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "verify"]);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("missing"));
+}
+
+#[test]
+fn sort_moves_unknown_types_to_fallback_directory() {
+    let fixtures = common::fixtures_dir().join("civitai");
+    let server = StubServer::start(move |request| {
+        let response = common::serve_fixture(&fixtures, request);
+        let Ok(mut info) = serde_json::from_slice::<serde_json::Value>(&response.body) else {
+            return response;
+        };
+        info["model"]["type"] = "Detection".into();
+        info["someNewField"] = true.into();
+        Response::new(200, info.to_string())
+    });
+    let general = tempfile::tempdir().unwrap();
+    std::fs::write(general.path().join("lora.safetensors"), common::LORA_FIXTURE).unwrap();
+    let config = general.path().join("config.toml");
+    std::fs::write(&config, "[sort]\nfallback_directory = \"unsorted\"\n").unwrap();
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "-t", config.to_str().unwrap(), "sort"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let sorted = general.path().join("unsorted/sdxl 1.0");
+    assert!(sorted.join("lora.safetensors").is_file());
+
+    let info: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(sorted.join("lora.civitai.info")).unwrap()).unwrap();
+    assert_eq!(info["someNewField"], true);
+}
+
+#[test]
+fn sort_follows_destination_template() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    std::fs::write(general.path().join("lora.safetensors"), common::LORA_FIXTURE).unwrap();
+    std::fs::write(general.path().join("lora.preview.png"), b"preview").unwrap();
+    std::fs::write(general.path().join("model.safetensors"), common::CHECKPOINT_FIXTURE).unwrap();
+    let config = general.path().join("config.toml");
+    let template = "{type}/{base_model}/{creator}/{model_name} [{nsfw}]/{file_name}";
+    std::fs::write(&config, format!("[sort]\ndestination = \"{}\"\n", template)).unwrap();
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "-t", config.to_str().unwrap(), "sort"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // The creator of the LoRA contains a colon, the checkpoint's is not in the fixtures
+    let sorted = general.path().join("loras/SDXL 1.0/Mr_ Detail/Detail Tweaker XL [sfw]");
+    assert!(sorted.join("lora.safetensors").is_file());
+    assert!(sorted.join("lora.preview.png").is_file());
+    let sorted = general.path().join("checkpoints/SD 1.5/unknown/Realistic Vision V6.0 B1 [sfw]");
+    assert!(sorted.join("model.safetensors").is_file());
+
+    let model_requests = server.requests().iter().filter(|r| r.path.starts_with("/models/")).count();
+    assert_eq!(model_requests, 2);
+}
+
+#[test]
+fn sort_rejects_unknown_placeholders() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    std::fs::write(general.path().join("lora.safetensors"), common::LORA_FIXTURE).unwrap();
+    let config = general.path().join("config.toml");
+    std::fs::write(&config, "[sort]\ndestination = \"{type}/{author}/{file_name}\"\n").unwrap();
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "-t", config.to_str().unwrap(), "sort"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unknown placeholder {author}"));
+    assert!(general.path().join("lora.safetensors").is_file());
+    assert!(server.requests().is_empty());
+}
+
+#[test]
+fn sort_renames_to_civitai_names() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    std::fs::write(general.path().join("model_v2_final(1).safetensors"), common::LORA_FIXTURE).unwrap();
+    std::fs::write(general.path().join("model_v2_final(1).preview.png"), b"preview").unwrap();
+    let sorted = general.path().join("loras/sdxl 1.0");
+    std::fs::create_dir_all(&sorted).unwrap();
+    std::fs::write(sorted.join("Detail Tweaker XL v1.0.safetensors"), b"another model").unwrap();
+    let config = general.path().join("config.toml");
+    std::fs::write(&config, "[sort]\nrename = \"model-version\"\n").unwrap();
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "-t", config.to_str().unwrap(), "sort"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(std::fs::read(sorted.join("Detail Tweaker XL v1.0.safetensors")).unwrap(), b"another model");
+    assert!(sorted.join("Detail Tweaker XL v1.0 (1).safetensors").is_file());
+    assert!(sorted.join("Detail Tweaker XL v1.0 (1).preview.png").is_file());
+    assert!(sorted.join("Detail Tweaker XL v1.0 (1).civitai.info").is_file());
+
+    let store: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(general.path().join(".model_sync.json")).unwrap()).unwrap();
+    let hashes = store["hashes"].as_object().unwrap();
+    assert_eq!(hashes.len(), 1);
+    assert!(hashes.contains_key("loras/sdxl 1.0/Detail Tweaker XL v1.0 (1).safetensors"));
+}
+
+#[test]
+fn sort_renames_to_civitai_file_name() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    std::fs::write(general.path().join("download.safetensors"), common::CHECKPOINT_FIXTURE).unwrap();
+    let config = general.path().join("config.toml");
+    std::fs::write(&config, "[sort]\nrename = \"civitai-file\"\n").unwrap();
+
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "-t", config.to_str().unwrap(), "sort"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let sorted = general.path().join("checkpoints/sd 1.5/realisticVisionV60B1_v60B1VAE.safetensors");
+    assert!(sorted.is_file());
+
+    // Found by its new name without hashing it again
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "-v", "3", "hash", sorted.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Using cached hash") && !stderr.contains("Calculating hash"), "{}", stderr);
+}
+
+#[test]
+fn sort_takes_finished_downloads_from_inboxes() {
+    let server = StubServer::with_fixtures(common::fixtures_dir().join("civitai"));
+    let general = tempfile::tempdir().unwrap();
+    let inbox = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(inbox.path().join("civitai/new")).unwrap();
+    std::fs::create_dir_all(inbox.path().join("training")).unwrap();
+    std::fs::write(inbox.path().join("civitai/new/lora.safetensors"), common::LORA_FIXTURE).unwrap();
+    std::fs::write(inbox.path().join("training/lora.safetensors"), common::LORA_FIXTURE).unwrap();
+    std::fs::write(inbox.path().join("model.safetensors"), common::CHECKPOINT_FIXTURE).unwrap();
+    std::fs::write(inbox.path().join("model.safetensors.part"), b"").unwrap();
+    let growing = inbox.path().join("growing.safetensors");
+    std::fs::write(&growing, common::CHECKPOINT_FIXTURE).unwrap();
+    let config = general.path().join("config.toml");
+    let contents = format!(
+        "[inbox]\ndirectories = [{:?}]\nexclude = [\"training\"]\ngrowth_check_ms = 300\n",
+        inbox.path().to_str().unwrap()
+    );
+    std::fs::write(&config, contents).unwrap();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let done = done.clone();
+        let growing = growing.clone();
+        std::thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                let mut file = std::fs::OpenOptions::new().append(true).open(&growing).unwrap();
+                file.write_all(&[0; 1024]).unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        })
+    };
+    let output = model_sync(&server, &[general.path().to_str().unwrap(), "-t", config.to_str().unwrap(), "-v", "2", "sort"]);
+    done.store(true, Ordering::SeqCst);
+    writer.join().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    assert!(general.path().join("loras/sdxl 1.0/lora.safetensors").is_file());
+    assert!(!inbox.path().join("civitai/new/lora.safetensors").exists());
+    assert!(inbox.path().join("training/lora.safetensors").is_file());
+    assert!(inbox.path().join("model.safetensors").is_file());
+    assert!(!general.path().join("checkpoints").exists());
+    // Its contents change, so it would not be found on CivitAI either way
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("growing.safetensors, it is still being written"), "{}", stderr);
+}
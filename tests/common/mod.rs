@@ -0,0 +1,159 @@
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::net::TcpListener;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Output;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: body.into(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// Minimal HTTP/1.1 server on a random local port, answering every request with `handler`.
+pub struct StubServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl StubServer {
+    pub fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&Request) -> Response + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let recorded = requests.clone();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let Some(request) = read_request(&mut BufReader::new(&stream)) else {
+                    continue;
+                };
+                recorded.lock().unwrap().push(request.clone());
+
+                let response = handler(&request);
+                let mut head = format!(
+                    "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    response.status,
+                    response.body.len()
+                );
+                for (name, value) in &response.headers {
+                    head.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                head.push_str("\r\n");
+                let _ = stream.write_all(head.as_bytes());
+                let _ = stream.write_all(&response.body);
+            }
+        });
+
+        Self { url, requests }
+    }
+
+    /// Serves `<fixtures>/<request path>.json` and answers 404 for everything else, the way the
+    /// CivitAI API does for unknown hashes.
+    pub fn with_fixtures<P: AsRef<Path>>(fixtures: P) -> Self {
+        let fixtures = fixtures.as_ref().to_path_buf();
+        Self::start(move |request| serve_fixture(&fixtures, request))
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+pub fn serve_fixture(fixtures: &Path, request: &Request) -> Response {
+    let path = request.path.split('?').next().unwrap_or_default();
+    let fixture = fixtures.join(format!("{}.json", path.trim_start_matches('/')));
+    match std::fs::read(&fixture) {
+        Ok(body) => Response::new(200, body),
+        Err(_) => Response::new(404, r#"{"error":"Model not found"}"#),
+    }
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> Option<Request> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).ok()?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok()? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    Some(Request { method, path, headers })
+}
+
+pub fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+/// Contents of the model files the hand-written CivitAI fixtures describe. The fixtures list the
+/// real SHA256 and AutoV2 of these contents, their other hashes are placeholders.
+pub const LORA_FIXTURE: &[u8] = b"model_sync fixture: detail tweaker lora\n";
+pub const CHECKPOINT_FIXTURE: &[u8] = b"model_sync fixture: realistic checkpoint\n";
+pub const LORA_FIXTURE_HASH: &str = "4E96766BB0E2B9A556EF874B4EC5BFE468FD377F721378FB6256B4EDC5971775";
+
+/// Command for the binary with the CivitAI API pointed at `server` and no API key from the
+/// environment or the user's credentials file.
+pub fn model_sync_command(server: &StubServer) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_model_sync"));
+    command
+        .env("CIVITAI_API_URL", &server.url)
+        .env_remove("CIVITAI_API_KEY")
+        .env("XDG_CONFIG_HOME", std::env::temp_dir().join("model_sync_no_config"));
+    command
+}
+
+pub fn model_sync(server: &StubServer, args: &[&str]) -> Output {
+    model_sync_command(server).args(args).output().unwrap()
+}